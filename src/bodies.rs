@@ -0,0 +1,125 @@
+use glam::{Quat, Vec3};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Initial conditions for a simulation.
+///
+/// `emitters` holds the indices of the bodies that act as light sources when rendering, and
+/// `galaxies` the galaxy each body belongs to, which is 0 unless a scenario builds several.
+#[derive(Clone, Debug, Default)]
+pub struct Bodies {
+    pub masses: Vec<f32>,
+    pub densities: Vec<f32>,
+    pub emitters: Vec<u32>,
+    pub galaxies: Vec<u32>,
+    pub positions: Vec<Vec3>,
+    pub velocities: Vec<Vec3>,
+}
+
+impl Bodies {
+    /// Generate `n_bodies` at rest, placed uniformly in the middle of a cube of size `world_size`.
+    ///
+    /// The same `seed` always produces bit-identical bodies, on every platform and backend.
+    pub fn random_cube(n_bodies: usize, world_size: f32, seed: u64) -> Self {
+        let mut bodies = Self {
+            masses: Vec::with_capacity(n_bodies),
+            densities: Vec::with_capacity(n_bodies),
+            emitters: Vec::with_capacity(n_bodies),
+            galaxies: vec![0; n_bodies],
+            positions: Vec::with_capacity(n_bodies),
+            velocities: vec![Vec3::ZERO; n_bodies],
+        };
+
+        let mut rng = seeded_rng(seed);
+        for n in 0..n_bodies {
+            let mass = rng.gen_range(0.5..=8.0) * rng.gen_range(0.5..=8.0);
+            let lower_bound = world_size / 5.0;
+            let upper_bound = 4.0 * lower_bound;
+            let position = Vec3::new(
+                rng.gen_range(lower_bound..=upper_bound),
+                rng.gen_range(lower_bound..=upper_bound),
+                rng.gen_range(lower_bound..=upper_bound),
+            );
+            //let density = rng.gen_range(1.0..=2.5);
+            let density = 1.0;
+            bodies.masses.push(mass);
+            bodies.positions.push(position);
+            bodies.densities.push(density);
+            if (position.x.round() as u32).is_multiple_of(20) || n == 0 {
+                bodies.emitters.push(n as u32);
+            }
+        }
+        bodies.emitters.shrink_to_fit();
+
+        bodies
+    }
+
+    pub fn push(&mut self, mass: f32, density: f32, position: Vec3, velocity: Vec3, emitter: bool) {
+        if emitter {
+            self.emitters.push(self.len() as u32);
+        }
+        self.masses.push(mass);
+        self.densities.push(density);
+        self.galaxies.push(0);
+        self.positions.push(position);
+        self.velocities.push(velocity);
+    }
+
+    /// Move all of `other`'s bodies onto the end of these.
+    pub fn append(&mut self, mut other: Bodies) {
+        let offset = self.len() as u32;
+        self.emitters
+            .extend(other.emitters.iter().map(|emitter| emitter + offset));
+        self.masses.append(&mut other.masses);
+        self.densities.append(&mut other.densities);
+        self.galaxies.append(&mut other.galaxies);
+        self.positions.append(&mut other.positions);
+        self.velocities.append(&mut other.velocities);
+    }
+
+    /// Shift every body by `offset` and add `velocity` to its own.
+    pub fn translate(&mut self, offset: Vec3, velocity: Vec3) {
+        self.positions.iter_mut().for_each(|p| *p += offset);
+        self.velocities.iter_mut().for_each(|v| *v += velocity);
+    }
+
+    /// Tag every body as belonging to `galaxy`.
+    pub fn set_galaxy(&mut self, galaxy: u32) {
+        self.galaxies.iter_mut().for_each(|g| *g = galaxy);
+    }
+
+    /// Rotate positions and velocities about the origin.
+    pub fn rotate(&mut self, rotation: Quat) {
+        self.positions.iter_mut().for_each(|p| *p = rotation * *p);
+        self.velocities.iter_mut().for_each(|v| *v = rotation * *v);
+    }
+
+    /// Shift positions and velocities so the centre of mass is at rest at the origin.
+    pub fn to_center_of_mass_frame(&mut self) {
+        let total_mass: f32 = self.masses.iter().sum();
+        if total_mass <= 0.0 {
+            return;
+        }
+        let weighted = |values: &[Vec3]| {
+            let sum: Vec3 = values.iter().zip(&self.masses).map(|(x, m)| *x * *m).sum();
+            sum / total_mass
+        };
+        let center = weighted(&self.positions);
+        let drift = weighted(&self.velocities);
+        self.translate(-center, -drift);
+    }
+
+    pub fn len(&self) -> usize {
+        self.masses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.masses.is_empty()
+    }
+}
+
+/// Portable generator for initial conditions; unlike `StdRng` its output is stable across
+/// platforms and `rand` versions.
+pub fn seeded_rng(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
@@ -0,0 +1,24 @@
+// Parameters and per-body buffers shared by the force and integrator kernels; the start of every
+// simulation shader, see `shader_source` in src/gpu.rs.
+
+struct Params {
+    time_step: f32,
+    g: f32,
+    softening: f32,
+    theta: f32,
+    eta: f32,
+    max_rung: u32,
+    force_model: u32,
+    softening_kernel: u32,
+    quadrupole: u32,
+};
+let FORCE_MODEL_STYLISED: u32 = 1u;
+
+@group(0) @binding(0) var<storage, read> masses: array<f32>;
+@group(0) @binding(1) var<uniform> params: Params;
+@group(0) @binding(3) var<storage, read_write> softening_lengths: array<f32>;
+//TODO: figure out if these access methods can be specified better
+@group(1) @binding(0) var<storage, read_write> positions: array<vec3<f32>>;
+@group(1) @binding(1) var<storage, read_write> velocities: array<vec3<f32>>;
+@group(1) @binding(2) var<storage, read_write> accelerations: array<vec3<f32>>;
+@group(1) @binding(3) var<storage, read_write> jerks: array<vec3<f32>>;
@@ -0,0 +1,126 @@
+use crate::{Bodies, Parameters, Simulation, Snapshot};
+use clap::Args;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const PREFIX: &str = "checkpoint-";
+const EXTENSION: &str = "nbody";
+
+/// When and where to write checkpoints.
+#[derive(Args, Clone, Debug)]
+pub struct CheckpointOptions {
+    /// Write a checkpoint every this many steps
+    #[arg(long, value_name = "STEPS")]
+    pub checkpoint_every: Option<u64>,
+
+    /// Write a checkpoint every this many seconds of wall-clock time
+    #[arg(long, value_name = "SECONDS")]
+    pub checkpoint_interval: Option<f64>,
+
+    /// Directory that checkpoints are written to and resumed from
+    #[arg(long, value_name = "DIR", default_value = "checkpoints")]
+    pub checkpoint_dir: PathBuf,
+
+    /// Number of most recent checkpoints to keep; older ones are deleted
+    #[arg(long, value_name = "N", default_value_t = 3)]
+    pub checkpoint_keep: usize,
+
+    /// Continue from the latest checkpoint in the checkpoint directory
+    #[arg(long, conflicts_with_all = ["scenario", "restore"])]
+    pub resume: bool,
+}
+
+/// Writes rotating checkpoints as a run progresses.
+pub struct Checkpointer {
+    options: CheckpointOptions,
+    last_saved: Instant,
+}
+
+impl Checkpointer {
+    /// A checkpointer for `options`; one is never due if they don't ask for checkpoints.
+    pub fn new(options: &CheckpointOptions) -> Self {
+        Self {
+            options: options.clone(),
+            last_saved: Instant::now(),
+        }
+    }
+
+    /// Whether a checkpoint is due after reaching `step`.
+    pub fn is_due(&self, step: u64) -> bool {
+        let by_steps = self
+            .options
+            .checkpoint_every
+            .is_some_and(|every| every > 0 && step.is_multiple_of(every));
+        let by_time = self
+            .options
+            .checkpoint_interval
+            .is_some_and(|seconds| self.last_saved.elapsed() >= Duration::from_secs_f64(seconds));
+        by_steps || by_time
+    }
+
+    /// Capture `simulation` after reaching `step` and save it as the newest checkpoint, if one
+    /// is due.
+    pub fn save_if_due<S: Simulation>(
+        &mut self,
+        simulation: &mut S,
+        bodies: &Bodies,
+        step: u64,
+        time: f64,
+        parameters: &Parameters,
+    ) -> io::Result<()> {
+        if self.is_due(step) {
+            self.save(&Snapshot::capture(
+                simulation, bodies, step, time, parameters,
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Write `snapshot` as the newest checkpoint, then delete all but the most recent ones.
+    ///
+    /// The file is written under a temporary name and then renamed, so a crash mid-write never
+    /// leaves a truncated checkpoint behind.
+    pub fn save(&mut self, snapshot: &Snapshot) -> io::Result<PathBuf> {
+        let dir = &self.options.checkpoint_dir;
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{PREFIX}{:012}.{EXTENSION}", snapshot.step));
+        let partial = path.with_extension("partial");
+        snapshot.save(&partial)?;
+        fs::rename(&partial, &path)?;
+        self.last_saved = Instant::now();
+
+        let checkpoints = list(dir)?;
+        let n_old = checkpoints
+            .len()
+            .saturating_sub(self.options.checkpoint_keep.max(1));
+        for old in &checkpoints[..n_old] {
+            fs::remove_file(old)?;
+        }
+        Ok(path)
+    }
+}
+
+/// The most recent checkpoint in `dir`, if any.
+pub fn latest(dir: &Path) -> io::Result<Option<PathBuf>> {
+    Ok(list(dir)?.pop())
+}
+
+/// Checkpoints in `dir`, oldest first.
+fn list(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut checkpoints = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or("");
+        if name.starts_with(PREFIX) && path.extension().is_some_and(|ext| ext == EXTENSION) {
+            checkpoints.push(path);
+        }
+    }
+    // Steps are zero-padded, so names sort by step
+    checkpoints.sort();
+    Ok(checkpoints)
+}
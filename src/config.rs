@@ -0,0 +1,325 @@
+use crate::checkpoint::{self, CheckpointOptions};
+use crate::force::ForceModel;
+use crate::integrator::{Integrator, MAX_RUNG};
+use crate::octree_maxdepth::{TreeBuilder, MAX_TREE_DEPTH};
+use crate::scenario::{ParameterOverrides, Scenario};
+use crate::snapshot::Snapshot;
+use crate::softening::SofteningKernel;
+use crate::{Bodies, Simulation};
+use clap::parser::ValueSource;
+use clap::{Args, CommandFactory, FromArgMatches, Parser};
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub const DEFAULT_WORLD_SIZE: f32 = 250.0;
+pub const DEFAULT_TIME_STEP: f32 = 0.1;
+pub const DEFAULT_G: f32 = 0.0066743; //can shift decimal as you see fit
+pub const DEFAULT_SOFTENING: f32 = 1.0;
+pub const DEFAULT_THETA: f32 = 0.5;
+pub const DEFAULT_LEAF_SIZE: u32 = 1;
+pub const DEFAULT_MAX_DEPTH: u32 = 16;
+pub const DEFAULT_ETA: f32 = 0.025;
+pub const DEFAULT_MIN_TIME_STEP: f32 = 0.001;
+pub const DEFAULT_MAX_TIME_STEP: f32 = 1.0;
+
+/// Command-line configuration shared by the nbody front-ends.
+#[derive(Parser, Clone, Debug)]
+#[command(version, about = "Gravitational n-body simulation")]
+pub struct Config {
+    /// Number of bodies to generate, when no scenario is given
+    #[arg(short, long, default_value_t = 250)]
+    pub n_bodies: usize,
+
+    /// TOML scenario file describing the bodies and parameters; command-line parameters take
+    /// precedence over the scenario's
+    #[arg(long)]
+    pub scenario: Option<PathBuf>,
+
+    /// Continue from this snapshot file instead of generating bodies; command-line parameters
+    /// take precedence over the snapshot's
+    #[arg(long, value_name = "SNAPSHOT", conflicts_with = "scenario")]
+    pub restore: Option<PathBuf>,
+
+    /// Snapshot file written at the end of headless runs, and by F5 in the window (F9 reloads
+    /// it); `snapshot.nbody` if not given
+    #[arg(long, value_name = "SNAPSHOT")]
+    pub dump: Option<PathBuf>,
+
+    /// Seed for the initial-condition generator; random if not given
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    #[command(flatten)]
+    pub parameters: Parameters,
+
+    /// Run this many steps without opening a window, then exit
+    #[arg(long, value_name = "STEPS")]
+    pub headless: Option<usize>,
+
+    /// Directory that headless runs write their results to
+    #[arg(long, default_value = "output")]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub checkpoints: CheckpointOptions,
+
+    /// Log energy, momentum and other diagnostics to `diagnostics.csv` in the output directory
+    /// every this many steps
+    #[arg(long, value_name = "STEPS")]
+    pub diagnostics_every: Option<u64>,
+
+    /// Body whose every position headless runs write to `tracks.csv`; may be repeated
+    #[arg(long = "track", value_name = "INDEX")]
+    pub tracked: Vec<usize>,
+
+    /// The scenario loaded from `scenario`
+    #[arg(skip)]
+    pub loaded_scenario: Option<Scenario>,
+
+    /// The snapshot loaded from `restore`
+    #[arg(skip)]
+    pub loaded_snapshot: Option<Snapshot>,
+
+    /// Arguments given on the command line, whose parameters take precedence over those of
+    /// scenarios and snapshots
+    #[arg(skip)]
+    pub command_line_ids: Vec<String>,
+}
+
+impl Config {
+    /// Parse the command line and load the scenario, if any. A seed is picked (and reported)
+    /// if neither gave one, so the run can be reproduced.
+    pub fn parse_args() -> Self {
+        let matches = Self::command().get_matches();
+        let mut config = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+        config.command_line_ids = (matches.ids())
+            .filter(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+            .map(|id| id.to_string())
+            .collect();
+        let command_line_ids = config.command_line_ids.clone();
+        let from_command_line = |id: &str| command_line_ids.iter().any(|given| given == id);
+
+        if let Some(path) = &config.scenario {
+            let scenario = Scenario::load(path).unwrap_or_else(|e| {
+                eprintln!("{}: {e}", path.display());
+                process::exit(1);
+            });
+            scenario
+                .parameter_overrides()
+                .apply(&mut config.parameters, from_command_line);
+            config.seed = config.seed.or(scenario.seed);
+            config.loaded_scenario = Some(scenario);
+        }
+        if config.checkpoints.resume {
+            let dir = &config.checkpoints.checkpoint_dir;
+            match checkpoint::latest(dir) {
+                Ok(Some(path)) => {
+                    eprintln!("resuming from {}", path.display());
+                    config.restore = Some(path);
+                }
+                Ok(None) => {
+                    eprintln!("{}: no checkpoint to resume from", dir.display());
+                    process::exit(1);
+                }
+                Err(e) => {
+                    eprintln!("{}: {e}", dir.display());
+                    process::exit(1);
+                }
+            }
+        }
+        if let Some(path) = &config.restore {
+            let snapshot = Snapshot::load(path).unwrap_or_else(|e| {
+                eprintln!("{}: {e}", path.display());
+                process::exit(1);
+            });
+            ParameterOverrides::from(&snapshot.parameters)
+                .apply(&mut config.parameters, from_command_line);
+            config.loaded_snapshot = Some(snapshot);
+        }
+        let parameters = &config.parameters;
+        if parameters.max_rung > MAX_RUNG {
+            eprintln!("max rung {} is deeper than {MAX_RUNG}", parameters.max_rung);
+            process::exit(1);
+        }
+        if parameters.leaf_size == 0 {
+            eprintln!("octree leaves have to hold at least one body");
+            process::exit(1);
+        }
+        if parameters.max_depth > MAX_TREE_DEPTH {
+            eprintln!(
+                "octree depth {} is deeper than {MAX_TREE_DEPTH}",
+                parameters.max_depth
+            );
+            process::exit(1);
+        }
+        let max_theta = 2.0 / 3f32.sqrt();
+        if parameters.theta <= 0.0 || parameters.theta > max_theta {
+            eprintln!("theta {} is outside (0, {max_theta}]", parameters.theta);
+            process::exit(1);
+        }
+        if parameters.max_rung > 0 && parameters.integrator != Integrator::Leapfrog {
+            eprintln!("block time steps need the leapfrog integrator");
+            process::exit(1);
+        }
+
+        let seed = *config.seed.get_or_insert_with(rand::random);
+        eprintln!("seed: {seed}");
+        config
+    }
+
+    /// Generate the initial bodies, from the scenario or else a random cube. Every front-end goes
+    /// through here, so a given seed yields the same input on the CPU, GPU and GPU Barnes-Hut
+    /// paths.
+    pub fn bodies(&self) -> Bodies {
+        let seed = self.seed.expect("seed is set by Config::parse_args");
+        match &self.loaded_scenario {
+            Some(scenario) => scenario.bodies(seed, &self.parameters),
+            None => Bodies::random_cube(self.n_bodies, self.parameters.world_size, seed),
+        }
+    }
+
+    /// `snapshot`, with the parameters given on the command line in place of its own, as it is
+    /// restored with `--restore`.
+    pub fn with_command_line_parameters(&self, mut snapshot: Snapshot) -> Snapshot {
+        let from_command_line = |id: &str| self.command_line_ids.iter().any(|given| given == id);
+        ParameterOverrides::from(&self.parameters)
+            .apply(&mut snapshot.parameters, |id| !from_command_line(id));
+        snapshot
+    }
+
+    /// The state to start from: the restored snapshot, or else freshly generated bodies.
+    pub fn initial_snapshot(&self) -> Snapshot {
+        match &self.loaded_snapshot {
+            Some(snapshot) => Snapshot {
+                parameters: self.parameters,
+                ..snapshot.clone()
+            },
+            None => Snapshot::initial(self.bodies(), &self.parameters),
+        }
+    }
+
+    /// Where to write snapshots.
+    pub fn dump_path(&self) -> &Path {
+        self.dump
+            .as_deref()
+            .unwrap_or_else(|| Path::new("snapshot.nbody"))
+    }
+}
+
+/// Physical and numerical parameters of a simulation.
+#[derive(Args, Clone, Copy, Debug)]
+pub struct Parameters {
+    /// Edge length of the cube that bodies are generated in
+    #[arg(long, default_value_t = DEFAULT_WORLD_SIZE)]
+    pub world_size: f32,
+
+    /// Length of a single simulation step, unless it is adaptive
+    #[arg(long, default_value_t = DEFAULT_TIME_STEP)]
+    pub time_step: f32,
+
+    /// Adapt each step to the largest acceleration |a| as eta * sqrt(softening / |a|), so steps
+    /// shrink during close encounters and grow again once they are over
+    #[arg(long)]
+    pub adaptive: bool,
+
+    /// Accuracy parameter of adaptive steps; smaller is more accurate but slower
+    #[arg(long, default_value_t = DEFAULT_ETA)]
+    pub eta: f32,
+
+    /// Shortest adaptive step
+    #[arg(long, default_value_t = DEFAULT_MIN_TIME_STEP)]
+    pub min_time_step: f32,
+
+    /// Longest adaptive step
+    #[arg(long, default_value_t = DEFAULT_MAX_TIME_STEP)]
+    pub max_time_step: f32,
+
+    /// Deepest block step rung: bodies on rung r take steps of 1/2^r of the whole step, with
+    /// rungs picked by the adaptive step criterion. 0 gives every body whole steps. A step has
+    /// 2^max_rung ticks, and Barnes-Hut rebuilds its octree on every one
+    #[arg(long, default_value_t = 0)]
+    pub max_rung: u32,
+
+    /// Gravitational constant
+    #[arg(short = 'G', long = "gravitational-constant", default_value_t = DEFAULT_G)]
+    pub g: f32,
+
+    /// Softening length, keeps forces finite at small separations; the smallest one with
+    /// adaptive softening
+    #[arg(long, default_value_t = DEFAULT_SOFTENING)]
+    pub softening: f32,
+
+    /// How forces are softened at small separations
+    #[arg(long, value_enum, default_value_t = SofteningKernel::default())]
+    pub softening_kernel: SofteningKernel,
+
+    /// Barnes-Hut opening angle; smaller is more accurate but slower
+    #[arg(long, default_value_t = DEFAULT_THETA)]
+    pub theta: f32,
+
+    /// Add the quadrupole moments of Barnes-Hut nodes to their monopoles, for the same accuracy
+    /// at a larger opening angle
+    #[arg(long)]
+    pub quadrupole: bool,
+
+    /// How nbody_cpu_bh builds its octree each step; nbody_gpu_bh builds its own on the GPU
+    #[arg(long, value_enum, default_value_t = TreeBuilder::default())]
+    pub tree_builder: TreeBuilder,
+
+    /// Most bodies a Barnes-Hut leaf holds before it splits, unless it's already max_depth deep
+    #[arg(long, default_value_t = DEFAULT_LEAF_SIZE)]
+    pub leaf_size: u32,
+
+    /// Deepest level of the Barnes-Hut octree, whose leaves hold however many bodies they get
+    #[arg(long, default_value_t = DEFAULT_MAX_DEPTH)]
+    pub max_depth: u32,
+
+    /// Law of attraction between bodies
+    #[arg(long, value_enum, default_value_t = ForceModel::default())]
+    pub force_model: ForceModel,
+
+    /// Time integration scheme
+    #[arg(long, value_enum, default_value_t = Integrator::default())]
+    pub integrator: Integrator,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self {
+            world_size: DEFAULT_WORLD_SIZE,
+            time_step: DEFAULT_TIME_STEP,
+            adaptive: false,
+            eta: DEFAULT_ETA,
+            min_time_step: DEFAULT_MIN_TIME_STEP,
+            max_time_step: DEFAULT_MAX_TIME_STEP,
+            max_rung: 0,
+            g: DEFAULT_G,
+            softening: DEFAULT_SOFTENING,
+            softening_kernel: SofteningKernel::default(),
+            theta: DEFAULT_THETA,
+            quadrupole: false,
+            tree_builder: TreeBuilder::default(),
+            leaf_size: DEFAULT_LEAF_SIZE,
+            max_depth: DEFAULT_MAX_DEPTH,
+            force_model: ForceModel::default(),
+            integrator: Integrator::default(),
+        }
+    }
+}
+
+impl Parameters {
+    /// Length of the next step of `simulation`: `time_step`, or if adaptive, derived from its
+    /// largest acceleration and kept between `min_time_step` and `max_time_step`. A NaN
+    /// acceleration, from a run that's blown up, gives a NaN step rather than the longest one.
+    pub fn next_time_step<S: Simulation>(&self, simulation: &mut S) -> f32 {
+        if !self.adaptive {
+            return self.time_step;
+        }
+        let max_acceleration = simulation.moments().max_acceleration;
+        let dt = self.eta * (self.softening / max_acceleration).sqrt();
+        if dt.is_nan() {
+            return dt;
+        }
+        dt.min(self.max_time_step).max(self.min_time_step)
+    }
+}
@@ -0,0 +1,203 @@
+use crate::diagnostics::Moments;
+use crate::force::ForceModel;
+use crate::frontend::{Backend, Positions};
+use crate::integrator::{BlockSteps, Force, State};
+use crate::softening::{self, SofteningKernel};
+use crate::{Bodies, Parameters, Simulation};
+use glam::Vec3;
+use rayon::prelude::*;
+use std::sync::Arc;
+use wgpu::{Device, Queue};
+
+/// Direct-sum O(N^2) simulation, evaluated in parallel on the CPU.
+pub struct CpuSimulation {
+    parameters: Parameters,
+    masses: Vec<f32>,
+    state: State,
+}
+
+impl CpuSimulation {
+    pub fn new(bodies: &Bodies, parameters: &Parameters) -> Self {
+        let mut force = DirectForce::new(&bodies.masses, parameters);
+        let mut state = State::new(bodies, parameters.integrator, &mut force);
+        if let Some(blocks) = BlockSteps::new(parameters) {
+            state.assign_rungs(&blocks, parameters.time_step);
+        }
+        Self {
+            parameters: *parameters,
+            masses: bodies.masses.clone(),
+            state,
+        }
+    }
+}
+
+/// Softened gravity summed over every pair.
+struct DirectForce<'a> {
+    masses: &'a [f32],
+    g: f32,
+    softening: f32,
+    kernel: SofteningKernel,
+    model: ForceModel,
+}
+
+impl<'a> DirectForce<'a> {
+    fn new(masses: &'a [f32], parameters: &Parameters) -> Self {
+        Self {
+            masses,
+            g: parameters.g,
+            softening: parameters.softening,
+            kernel: parameters.softening_kernel,
+            model: parameters.force_model,
+        }
+    }
+
+    /// Separation of bodies `n` and `n2`, its square, and their softening length.
+    fn pair(&self, positions: &[Vec3], lengths: &[f32], n: usize, n2: usize) -> (Vec3, f32, f32) {
+        let distance = positions[n2] - positions[n];
+        let softening = softening::pair_length(lengths[n], lengths[n2]);
+        (distance, distance.length_squared(), softening)
+    }
+
+    fn acceleration(
+        &self,
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        lengths: &[f32],
+        n: usize,
+    ) -> Vec3 {
+        (0..positions.len())
+            .filter(|n2| *n2 != n)
+            .map(|n2| {
+                let (distance, r_sqrd, softening) = self.pair(positions, lengths, n, n2);
+                let force = self.g * self.masses[n2] * self.kernel.force(r_sqrd, softening);
+                force * self.model.bias(velocities[n], distance) * distance
+            })
+            .sum()
+    }
+
+    /// Time derivative of the unbiased acceleration of body `n`, whatever the force model.
+    fn jerk(&self, positions: &[Vec3], velocities: &[Vec3], lengths: &[f32], n: usize) -> Vec3 {
+        (0..positions.len())
+            .filter(|n2| *n2 != n)
+            .map(|n2| {
+                let (distance, r_sqrd, softening) = self.pair(positions, lengths, n, n2);
+                let velocity = velocities[n2] - velocities[n];
+                let force = self.kernel.force(r_sqrd, softening);
+                let slope = self.kernel.force_slope(r_sqrd, softening);
+                self.g
+                    * self.masses[n2]
+                    * (force * velocity + slope * distance.dot(velocity) * distance)
+            })
+            .sum()
+    }
+}
+
+impl Force for DirectForce<'_> {
+    fn evaluate(
+        &mut self,
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        accelerations: &mut [Vec3],
+        jerks: Option<&mut [Vec3]>,
+    ) {
+        let lengths = self.kernel.lengths(positions, self.softening);
+        accelerations
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(n, a)| *a = self.acceleration(positions, velocities, &lengths, n));
+        if let Some(jerks) = jerks {
+            jerks
+                .par_iter_mut()
+                .enumerate()
+                .for_each(|(n, j)| *j = self.jerk(positions, velocities, &lengths, n));
+        }
+    }
+
+    fn evaluate_active(
+        &mut self,
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        active: &[usize],
+        accelerations: &mut [Vec3],
+    ) {
+        let lengths = self.kernel.lengths(positions, self.softening);
+        let active_accelerations: Vec<Vec3> = active
+            .par_iter()
+            .map(|n| self.acceleration(positions, velocities, &lengths, *n))
+            .collect();
+        for (n, a) in active.iter().zip(active_accelerations) {
+            accelerations[*n] = a;
+        }
+    }
+}
+
+impl Simulation for CpuSimulation {
+    fn step(&mut self, dt: f32) {
+        let mut force = DirectForce::new(&self.masses, &self.parameters);
+        match BlockSteps::new(&self.parameters) {
+            Some(blocks) => self.state.block_step(&blocks, dt, &mut force),
+            None => self.state.step(self.parameters.integrator, dt, &mut force),
+        }
+    }
+
+    fn n_bodies(&self) -> usize {
+        self.masses.len()
+    }
+
+    fn positions(&mut self) -> Vec<Vec3> {
+        self.state.positions.clone()
+    }
+
+    fn velocities(&mut self) -> Vec<Vec3> {
+        self.state.velocities.clone()
+    }
+
+    fn accelerations(&mut self) -> Vec<Vec3> {
+        self.state.accelerations.clone()
+    }
+
+    fn jerks(&mut self) -> Vec<Vec3> {
+        self.state.jerks.clone()
+    }
+
+    fn rungs(&mut self) -> Vec<u32> {
+        self.state.rungs.clone()
+    }
+
+    fn moments(&mut self) -> Moments {
+        let state = &self.state;
+        Moments::compute(
+            &self.masses,
+            &state.positions,
+            &state.velocities,
+            &state.accelerations,
+        )
+    }
+
+    fn set_accelerations(&mut self, accelerations: &[Vec3], jerks: &[Vec3]) {
+        self.state.accelerations.copy_from_slice(accelerations);
+        self.state.jerks.copy_from_slice(jerks);
+    }
+
+    fn set_rungs(&mut self, rungs: &[u32]) {
+        let max_rung = self.parameters.max_rung;
+        let rungs = rungs.iter().map(|rung| (*rung).min(max_rung));
+        self.state.rungs = rungs.collect();
+    }
+}
+
+impl Backend for CpuSimulation {
+    const COMPUTES_JERKS: bool = true;
+
+    fn create(
+        _device: impl FnOnce() -> (Arc<Device>, Arc<Queue>),
+        bodies: &Bodies,
+        parameters: &Parameters,
+    ) -> Self {
+        Self::new(bodies, parameters)
+    }
+
+    fn current_positions(&self) -> Positions<'_> {
+        Positions::Host(&self.state.positions)
+    }
+}
@@ -0,0 +1,312 @@
+use crate::diagnostics::Moments;
+use crate::force::ForceModel;
+use crate::frontend::{Backend, Positions};
+use crate::integrator::{BlockSteps, Force, State};
+use crate::octree_maxdepth::{
+    add_quadrupoles, OctreeNode, TreeBuilder, TreeShape, NODETYPE_LEAFBODY,
+};
+use crate::softening::SofteningKernel;
+use crate::{Bodies, Parameters, Simulation};
+use glam::Vec3;
+use rayon::prelude::*;
+use std::sync::Arc;
+use wgpu::{Device, Queue};
+
+/// Barnes-Hut O(N log N) simulation, evaluated in parallel on the CPU.
+///
+/// The octree is rebuilt before every force evaluation and walked just as `nbodybh.wgsl` walks
+/// it, so this doubles as a reference for the GPU traversal. Its nodes carry no velocities, so
+/// integrators that need jerks aren't supported.
+pub struct CpuBhSimulation {
+    parameters: Parameters,
+    masses: Vec<f32>,
+    state: State,
+}
+
+impl CpuBhSimulation {
+    pub fn new(bodies: &Bodies, parameters: &Parameters) -> Self {
+        let integrator = parameters.integrator;
+        assert!(
+            !integrator.needs_jerks(),
+            "the Barnes-Hut simulation can't compute jerks for {integrator:?}"
+        );
+        let mut force = TreeForce::new(&bodies.masses, parameters);
+        let mut state = State::new(bodies, integrator, &mut force);
+        if let Some(blocks) = BlockSteps::new(parameters) {
+            state.assign_rungs(&blocks, parameters.time_step);
+        }
+        Self {
+            parameters: *parameters,
+            masses: bodies.masses.clone(),
+            state,
+        }
+    }
+}
+
+/// Softened gravity of the octree's nodes, opened unless they're far enough away by `theta`;
+/// see `is_far`. Nodes that aren't opened can add their quadrupole moments to their monopoles.
+struct TreeForce<'a> {
+    masses: &'a [f32],
+    g: f32,
+    theta: f32,
+    quadrupole: bool,
+    builder: TreeBuilder,
+    shape: TreeShape,
+    softening: f32,
+    kernel: SofteningKernel,
+    model: ForceModel,
+}
+
+impl<'a> TreeForce<'a> {
+    fn new(masses: &'a [f32], parameters: &Parameters) -> Self {
+        Self {
+            masses,
+            g: parameters.g,
+            theta: parameters.theta,
+            quadrupole: parameters.quadrupole,
+            builder: parameters.tree_builder,
+            shape: TreeShape::new(parameters),
+            softening: parameters.softening,
+            kernel: parameters.softening_kernel,
+            model: parameters.force_model,
+        }
+    }
+
+    /// Acceleration of a body at `position` moving at `velocity`, softened by `softening`. Nodes
+    /// don't know which bodies they hold, so that's the body's own length, and the body's own
+    /// Leaf-Body is told apart by lying right on it. The walk follows the ropes, summing a node
+    /// and skipping past it or else opening it.
+    fn acceleration(
+        &self,
+        octree: &[OctreeNode],
+        position: Vec3,
+        velocity: Vec3,
+        softening: f32,
+    ) -> Vec3 {
+        let pull = |node: &OctreeNode| {
+            let distance = node.center_of_mass - position;
+            if node.node_type == NODETYPE_LEAFBODY && distance == Vec3::ZERO {
+                return Vec3::ZERO;
+            }
+            let force = self.kernel.force(distance.length_squared(), softening);
+            let mut pull = node.total_mass * force * distance;
+            if self.quadrupole && node.node_type != NODETYPE_LEAFBODY {
+                pull += quadrupole_pull(node, position);
+            }
+            self.g * self.model.bias(velocity, distance) * pull
+        };
+
+        let mut acceleration = Vec3::ZERO;
+        let mut index = 0;
+        loop {
+            let node = &octree[index as usize];
+            if node.node_type == NODETYPE_LEAFBODY || is_far(node, position, self.theta) {
+                acceleration += pull(node);
+                index = node.skip;
+            } else {
+                index = node.next;
+            }
+            if index == 0 {
+                break;
+            }
+        }
+        acceleration
+    }
+}
+
+/// Whether `node` is far enough from `position` to be summed whole rather than opened: further
+/// from its centre of mass than `range / theta`, plus however far that is from the middle of its
+/// bounds. Unlike comparing `range / distance` with `theta`, this never accepts a node whose
+/// bounds hold the body (for theta up to 2/sqrt(3)), where the quadrupole expansion diverges.
+fn is_far(node: &OctreeNode, position: Vec3, theta: f32) -> bool {
+    let center = 0.5 * (node.pos_min + node.pos_max);
+    let offset = node.center_of_mass.distance(center);
+    position.distance(node.center_of_mass) > node.range / theta + offset
+}
+
+/// Pull of `node`'s quadrupole moment on a body at `position`, per unit G. Only far nodes are
+/// approximated, so it goes unsoftened.
+fn quadrupole_pull(node: &OctreeNode, position: Vec3) -> Vec3 {
+    let r = position - node.center_of_mass;
+    let (diagonal, off) = (node.quadrupole_diagonal, node.quadrupole_off_diagonal);
+    let q_r = diagonal * r
+        + Vec3::new(
+            off.x * r.y + off.y * r.z,
+            off.x * r.x + off.z * r.z,
+            off.y * r.x + off.z * r.y,
+        );
+    let inv_r2 = 1.0 / r.length_squared();
+    let inv_r5 = inv_r2 * inv_r2 * inv_r2.sqrt();
+    inv_r5 * (q_r - 2.5 * r.dot(q_r) * inv_r2 * r)
+}
+
+impl Force for TreeForce<'_> {
+    fn evaluate(
+        &mut self,
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        accelerations: &mut [Vec3],
+        _jerks: Option<&mut [Vec3]>,
+    ) {
+        let active: Vec<usize> = (0..positions.len()).collect();
+        self.evaluate_active(positions, velocities, &active, accelerations);
+    }
+
+    fn evaluate_active(
+        &mut self,
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        active: &[usize],
+        accelerations: &mut [Vec3],
+    ) {
+        let mut octree = self.builder.build(positions, self.masses, self.shape);
+        if self.quadrupole {
+            add_quadrupoles(&mut octree);
+        }
+        let lengths = self.kernel.lengths(positions, self.softening);
+        let active_accelerations: Vec<Vec3> = active
+            .par_iter()
+            .map(|n| {
+                let (position, velocity) = (positions[*n], velocities[*n]);
+                self.acceleration(&octree, position, velocity, lengths[*n])
+            })
+            .collect();
+        for (n, a) in active.iter().zip(active_accelerations) {
+            accelerations[*n] = a;
+        }
+    }
+}
+
+impl Simulation for CpuBhSimulation {
+    fn step(&mut self, dt: f32) {
+        let mut force = TreeForce::new(&self.masses, &self.parameters);
+        match BlockSteps::new(&self.parameters) {
+            Some(blocks) => self.state.block_step(&blocks, dt, &mut force),
+            None => self.state.step(self.parameters.integrator, dt, &mut force),
+        }
+    }
+
+    fn n_bodies(&self) -> usize {
+        self.masses.len()
+    }
+
+    fn positions(&mut self) -> Vec<Vec3> {
+        self.state.positions.clone()
+    }
+
+    fn velocities(&mut self) -> Vec<Vec3> {
+        self.state.velocities.clone()
+    }
+
+    fn accelerations(&mut self) -> Vec<Vec3> {
+        self.state.accelerations.clone()
+    }
+
+    fn jerks(&mut self) -> Vec<Vec3> {
+        self.state.jerks.clone()
+    }
+
+    fn rungs(&mut self) -> Vec<u32> {
+        self.state.rungs.clone()
+    }
+
+    fn moments(&mut self) -> Moments {
+        let state = &self.state;
+        Moments::compute(
+            &self.masses,
+            &state.positions,
+            &state.velocities,
+            &state.accelerations,
+        )
+    }
+
+    fn set_accelerations(&mut self, accelerations: &[Vec3], jerks: &[Vec3]) {
+        self.state.accelerations.copy_from_slice(accelerations);
+        self.state.jerks.copy_from_slice(jerks);
+    }
+
+    fn set_rungs(&mut self, rungs: &[u32]) {
+        let max_rung = self.parameters.max_rung;
+        let rungs = rungs.iter().map(|rung| (*rung).min(max_rung));
+        self.state.rungs = rungs.collect();
+    }
+}
+
+impl Backend for CpuBhSimulation {
+    const COMPUTES_JERKS: bool = false;
+
+    fn create(
+        _device: impl FnOnce() -> (Arc<Device>, Arc<Queue>),
+        bodies: &Bodies,
+        parameters: &Parameters,
+    ) -> Self {
+        Self::new(bodies, parameters)
+    }
+
+    fn current_positions(&self) -> Positions<'_> {
+        Positions::Host(&self.state.positions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bodies::seeded_rng;
+    use crate::config::DEFAULT_G;
+    use crate::models::plummer;
+    use crate::CpuSimulation;
+
+    /// Mean and largest error of the Barnes-Hut accelerations, relative to the direct sum's.
+    fn errors(bodies: &Bodies, parameters: &Parameters) -> (f32, f32) {
+        let exact = CpuSimulation::new(bodies, parameters).accelerations();
+        let approximate = CpuBhSimulation::new(bodies, parameters).accelerations();
+        let errors: Vec<f32> = (exact.iter().zip(&approximate))
+            .map(|(exact, approximate)| (*approximate - *exact).length() / exact.length())
+            .collect();
+        let mean = errors.iter().sum::<f32>() / errors.len() as f32;
+        (mean, errors.iter().copied().fold(0.0, f32::max))
+    }
+
+    #[test]
+    fn accelerations_match_direct_sum() {
+        let cube = Bodies::random_cube(2000, 250.0, 7);
+        let sphere = plummer(&mut seeded_rng(1), 1000, 1.0, 1.0, 1.0);
+        for (bodies, g, softening) in [(cube, DEFAULT_G, 1.0), (sphere, 1.0, 0.01)] {
+            for theta in [0.3, 0.6, 0.9] {
+                let monopole = Parameters {
+                    g,
+                    softening,
+                    theta,
+                    ..Default::default()
+                };
+                let quadrupole = Parameters {
+                    quadrupole: true,
+                    ..monopole
+                };
+                let (mean, max) = errors(&bodies, &monopole);
+                let (quadrupole_mean, quadrupole_max) = errors(&bodies, &quadrupole);
+                assert!(
+                    mean < 0.05 * theta && max < theta,
+                    "theta {theta}: {mean}, {max}"
+                );
+                //quadrupoles only ever help, which they can't if nodes holding the body are summed
+                assert!(
+                    quadrupole_mean < 0.5 * mean && quadrupole_max < max,
+                    "theta {theta}: {quadrupole_mean}, {quadrupole_max}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unsoftened_accelerations_match_direct_sum() {
+        let bodies = Bodies::random_cube(500, 250.0, 3);
+        let parameters = Parameters {
+            softening: 0.0,
+            theta: 0.3,
+            ..Default::default()
+        };
+        let (mean, max) = errors(&bodies, &parameters);
+        assert!(mean < 0.05 * 0.3 && max < 0.3, "{mean}, {max}");
+    }
+}
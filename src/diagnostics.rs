@@ -0,0 +1,276 @@
+use crate::softening;
+use crate::{Config, Parameters, Simulation};
+use glam::{DVec3, Vec3};
+use rayon::prelude::*;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Mass-weighted sums and bounds over the bodies, cheap enough to take every step. The GPU
+/// backends compute these on the device.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Moments {
+    pub total_mass: f64,
+    pub kinetic_energy: f64,
+    pub momentum: DVec3,
+    pub angular_momentum: DVec3,
+    pub center_of_mass: DVec3,
+    /// Corners of the axis-aligned bounding box of the positions
+    pub bounds_min: Vec3,
+    pub bounds_max: Vec3,
+    /// Largest speed of any body, NaN if any is
+    pub max_speed: f32,
+    /// Largest acceleration of any body, which adaptive steps are chosen from; NaN if any is
+    pub max_acceleration: f32,
+}
+
+impl Moments {
+    pub fn compute(
+        masses: &[f32],
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        accelerations: &[Vec3],
+    ) -> Self {
+        let mut moments = Self {
+            bounds_min: Vec3::splat(f32::INFINITY),
+            bounds_max: Vec3::splat(f32::NEG_INFINITY),
+            ..Default::default()
+        };
+        for ((m, p), v) in masses.iter().zip(positions).zip(velocities) {
+            let (m, position, v) = (*m as f64, p.as_dvec3(), v.as_dvec3());
+            moments.total_mass += m;
+            moments.kinetic_energy += 0.5 * m * v.length_squared();
+            moments.momentum += m * v;
+            moments.angular_momentum += position.cross(m * v);
+            moments.center_of_mass += m * position;
+            moments.bounds_min = moments.bounds_min.min(*p);
+            moments.bounds_max = moments.bounds_max.max(*p);
+        }
+        if moments.total_mass > 0.0 {
+            moments.center_of_mass /= moments.total_mass;
+        }
+        moments.max_speed = velocities.iter().map(|v| v.length()).fold(0.0, max_or_nan);
+        moments.max_acceleration = accelerations
+            .iter()
+            .map(|a| a.length())
+            .fold(0.0, max_or_nan);
+        moments
+    }
+}
+
+/// The larger of `a` and `b`, or NaN if either is, unlike `f32::max`; a blown-up run shows in
+/// the maxima rather than leaving them at zero. `reduce.wgsl` folds the same way.
+fn max_or_nan(a: f32, b: f32) -> f32 {
+    if b.is_nan() || b > a {
+        b
+    } else {
+        a
+    }
+}
+
+/// Conserved quantities and other global measures of a system's state, accumulated in f64.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Diagnostics {
+    pub kinetic_energy: f64,
+    /// Softened pairwise potential energy, consistent with the force law
+    pub potential_energy: f64,
+    pub momentum: DVec3,
+    /// Angular momentum about the origin
+    pub angular_momentum: DVec3,
+    pub center_of_mass: DVec3,
+    /// Sum of |m v|, the scale that momentum drift is measured against
+    pub momentum_scale: f64,
+    /// Sum of |m r × v|, the scale that angular momentum drift is measured against
+    pub angular_momentum_scale: f64,
+}
+
+impl Diagnostics {
+    /// Measure the state of a set of bodies. The potential energy is a direct O(N^2) sum.
+    pub fn compute(
+        masses: &[f32],
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        parameters: &Parameters,
+    ) -> Self {
+        let mut diagnostics = Self::default();
+        let mut total_mass = 0.0;
+        for ((m, p), v) in masses.iter().zip(positions).zip(velocities) {
+            let (m, p, v) = (*m as f64, p.as_dvec3(), v.as_dvec3());
+            let momentum = m * v;
+            let angular_momentum = p.cross(momentum);
+            total_mass += m;
+            diagnostics.kinetic_energy += 0.5 * m * v.length_squared();
+            diagnostics.momentum += momentum;
+            diagnostics.angular_momentum += angular_momentum;
+            diagnostics.center_of_mass += m * p;
+            diagnostics.momentum_scale += momentum.length();
+            diagnostics.angular_momentum_scale += angular_momentum.length();
+        }
+        if total_mass > 0.0 {
+            diagnostics.center_of_mass /= total_mass;
+        }
+        diagnostics.potential_energy = potential_energy(masses, positions, parameters);
+        diagnostics
+    }
+
+    /// Measure the current state of `simulation`, reading it back from the GPU if need be.
+    pub fn measure<S: Simulation>(
+        simulation: &mut S,
+        masses: &[f32],
+        parameters: &Parameters,
+    ) -> Self {
+        let positions = simulation.positions();
+        let velocities = simulation.velocities();
+        Self::compute(masses, &positions, &velocities, parameters)
+    }
+
+    pub fn total_energy(&self) -> f64 {
+        self.kinetic_energy + self.potential_energy
+    }
+
+    /// 2K/|W|, which is 1 for a system in virial equilibrium.
+    pub fn virial_ratio(&self) -> f64 {
+        2.0 * self.kinetic_energy / self.potential_energy.abs()
+    }
+
+    /// Relative changes in total energy, momentum and angular momentum since `initial`.
+    ///
+    /// Momenta are usually zero to begin with, so their drift is measured against the sum of
+    /// the bodies' individual magnitudes rather than the total.
+    pub fn drift(&self, initial: &Self) -> (f64, f64, f64) {
+        let relative = |change: f64, scale: f64| if scale > 0.0 { change / scale } else { change };
+        (
+            relative(
+                self.total_energy() - initial.total_energy(),
+                initial.total_energy().abs(),
+            ),
+            relative(
+                (self.momentum - initial.momentum).length(),
+                initial.momentum_scale,
+            ),
+            relative(
+                (self.angular_momentum - initial.angular_momentum).length(),
+                initial.angular_momentum_scale,
+            ),
+        )
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "total energy {:.6e}, virial ratio {:.3}",
+            self.total_energy(),
+            self.virial_ratio()
+        )
+    }
+}
+
+fn potential_energy(masses: &[f32], positions: &[Vec3], parameters: &Parameters) -> f64 {
+    let g = parameters.g as f64;
+    let kernel = parameters.softening_kernel;
+    let lengths = kernel.lengths(positions, parameters.softening);
+    (0..masses.len())
+        .into_par_iter()
+        .map(|i| {
+            let p = positions[i].as_dvec3();
+            let sum: f64 = (i + 1..masses.len())
+                .map(|j| {
+                    let r = (positions[j].as_dvec3() - p).length();
+                    let softening = softening::pair_length(lengths[i], lengths[j]) as f64;
+                    masses[j] as f64 * kernel.potential(r, softening)
+                })
+                .sum();
+            g * masses[i] as f64 * sum
+        })
+        .sum()
+}
+
+/// `diagnostics.csv` time series, with drift relative to the first row.
+pub struct DiagnosticsLog {
+    csv: BufWriter<File>,
+    every: u64,
+    initial: Option<Diagnostics>,
+}
+
+impl DiagnosticsLog {
+    /// Start the log `config` asks for, if any, in its output directory.
+    pub fn for_config(config: &Config) -> io::Result<Option<Self>> {
+        (config.diagnostics_every)
+            .map(|every| Self::create(&config.output, every))
+            .transpose()
+    }
+
+    /// Start a log in `output_dir` that records every `every` steps.
+    pub fn create(output_dir: &Path, every: u64) -> io::Result<Self> {
+        fs::create_dir_all(output_dir)?;
+        let mut csv = BufWriter::new(File::create(output_dir.join("diagnostics.csv"))?);
+        writeln!(
+            csv,
+            "step,time,kinetic_energy,potential_energy,total_energy,virial_ratio,\
+             momentum_x,momentum_y,momentum_z,angular_momentum_x,angular_momentum_y,\
+             angular_momentum_z,center_of_mass_x,center_of_mass_y,center_of_mass_z,\
+             energy_drift,momentum_drift,angular_momentum_drift"
+        )?;
+        Ok(Self {
+            csv,
+            every: every.max(1),
+            initial: None,
+        })
+    }
+
+    /// Measure and log `simulation` if `step` is due, or it is the first step logged.
+    pub fn record<S: Simulation>(
+        &mut self,
+        simulation: &mut S,
+        masses: &[f32],
+        parameters: &Parameters,
+        step: u64,
+        time: f64,
+    ) -> io::Result<Option<Diagnostics>> {
+        if self.initial.is_some() && !step.is_multiple_of(self.every) {
+            return Ok(None);
+        }
+        let diagnostics = Diagnostics::measure(simulation, masses, parameters);
+        self.write(step, time, &diagnostics)?;
+        Ok(Some(diagnostics))
+    }
+
+    /// Measure drift from the next row logged rather than the first, e.g. after a restore.
+    pub fn reset_baseline(&mut self) {
+        self.initial = None;
+    }
+
+    /// Append a row for `diagnostics` measured at `step` and `time`.
+    pub fn write(&mut self, step: u64, time: f64, diagnostics: &Diagnostics) -> io::Result<()> {
+        let initial = self.initial.get_or_insert(*diagnostics);
+        let (energy_drift, momentum_drift, angular_momentum_drift) = diagnostics.drift(initial);
+        let (p, l, com) = (
+            diagnostics.momentum,
+            diagnostics.angular_momentum,
+            diagnostics.center_of_mass,
+        );
+        writeln!(
+            self.csv,
+            "{step},{time},{},{},{},{},{},{},{},{},{},{},{},{},{},{energy_drift},\
+             {momentum_drift},{angular_momentum_drift}",
+            diagnostics.kinetic_energy,
+            diagnostics.potential_energy,
+            diagnostics.total_energy(),
+            diagnostics.virial_ratio(),
+            p.x,
+            p.y,
+            p.z,
+            l.x,
+            l.y,
+            l.z,
+            com.x,
+            com.y,
+            com.z,
+        )?;
+        // Flush every row, so the series survives a crash
+        self.csv.flush()
+    }
+}
@@ -0,0 +1,30 @@
+use clap::ValueEnum;
+use glam::Vec3;
+use serde::Deserialize;
+
+/// Law of attraction between bodies, shared by every backend.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ForceModel {
+    /// Softened Newtonian gravity; conserves energy and momentum up to integration error
+    #[default]
+    Newtonian,
+    /// Newtonian gravity weakened for bodies heading towards each other, as the simulation
+    /// originally did; only for reproducing its visuals, as it breaks Newton's third law
+    Stylised,
+}
+
+impl ForceModel {
+    /// Factor on the attraction towards a body at `separation` from one moving at `velocity`.
+    pub fn bias(self, velocity: Vec3, separation: Vec3) -> f32 {
+        match self {
+            ForceModel::Newtonian => 1.0,
+            ForceModel::Stylised => {
+                // The angle between the velocity and the pull, reined in by the power
+                let cos =
+                    velocity.dot(separation) / (separation.length() * velocity.length() + 1.0);
+                cos.acos().powf(0.15)
+            }
+        }
+    }
+}
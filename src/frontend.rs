@@ -0,0 +1,235 @@
+use crate::checkpoint::Checkpointer;
+use crate::diagnostics::DiagnosticsLog;
+use crate::gpu::{create_buffer_init, request_headless_device, write_buffer};
+use crate::render::{Renderer, WindowContext};
+use crate::{headless, Bodies, Config, Parameters, Simulation, Snapshot};
+use glam::Vec3;
+use std::sync::Arc;
+use std::{mem, process};
+use wgpu::*;
+use winit::{
+    event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+    event_loop::{ControlFlow, EventLoop},
+    window::Window,
+};
+
+/// Where a backend keeps the current positions, for the renderer to read.
+pub enum Positions<'a> {
+    /// Already on the device, so they're rendered where they are
+    Device(&'a Buffer),
+    /// On the host, so they're uploaded after every step
+    Host(&'a [Vec3]),
+}
+
+/// A backend the front-end can drive.
+pub trait Backend: Simulation + Sized + 'static {
+    /// Whether it computes the jerks that some integrators need.
+    const COMPUTES_JERKS: bool;
+
+    /// Set up the simulation of `bodies`. `device` is only called by backends that run on one:
+    /// it gives the window's device, or in headless runs, one requested for the purpose.
+    fn create(
+        device: impl FnOnce() -> (Arc<Device>, Arc<Queue>),
+        bodies: &Bodies,
+        parameters: &Parameters,
+    ) -> Self;
+
+    fn current_positions(&self) -> Positions<'_>;
+}
+
+/// Run backend `B` as the command line asks: headless for a number of steps, or else in a
+/// window until it's closed, with F5 and F9 saving and restoring snapshots.
+pub fn run<B: Backend>() {
+    let config = Config::parse_args();
+    let integrator = config.parameters.integrator;
+    if integrator.needs_jerks() && !B::COMPUTES_JERKS {
+        eprintln!("integrator {integrator:?} needs jerks, which Barnes-Hut doesn't compute");
+        process::exit(1);
+    }
+    let snapshot = config.initial_snapshot();
+
+    if let Some(n_steps) = config.headless {
+        let device = || pollster::block_on(request_headless_device());
+        let mut simulation = create::<B>(device, &snapshot);
+        if let Err(e) = headless::run(&mut simulation, &snapshot, n_steps, &config) {
+            eprintln!("{}: {e}", config.output.display());
+            process::exit(1);
+        }
+        return;
+    }
+
+    let event_loop = EventLoop::new();
+    let window = Window::new(&event_loop).unwrap();
+    pollster::block_on(run_windowed::<B>(event_loop, window, config, snapshot));
+}
+
+/// Set up the simulation for `snapshot`, carrying on where it left off.
+fn create<B: Backend>(
+    device: impl FnOnce() -> (Arc<Device>, Arc<Queue>),
+    snapshot: &Snapshot,
+) -> B {
+    let mut simulation = B::create(device, &snapshot.bodies, &snapshot.parameters);
+    snapshot.restore_history(&mut simulation);
+    simulation
+}
+
+/// Set up the simulation and renderer for `snapshot`, with a buffer to upload the positions to
+/// if the backend keeps them on the host.
+fn load<B: Backend>(context: &WindowContext, snapshot: &Snapshot) -> (B, Renderer, Option<Buffer>) {
+    let device = || (context.device.clone(), context.queue.clone());
+    let simulation = create::<B>(device, snapshot);
+    let renderer = Renderer::new(&context.device, context.config.format, &snapshot.bodies);
+    let pos_buffer = match simulation.current_positions() {
+        Positions::Device(_) => None,
+        Positions::Host(positions) => Some(create_buffer_init(
+            &context.device,
+            "pos_buffer",
+            &positions.to_vec(),
+            BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        )),
+    };
+    (simulation, renderer, pos_buffer)
+}
+
+async fn run_windowed<B: Backend>(
+    event_loop: EventLoop<()>,
+    window: Window,
+    config: Config,
+    snapshot: Snapshot,
+) {
+    let mut context = WindowContext::new(&window).await;
+    let (mut simulation, mut renderer, mut pos_buffer) = load::<B>(&context, &snapshot);
+    let Snapshot {
+        mut step,
+        mut time,
+        mut parameters,
+        mut bodies,
+        ..
+    } = snapshot;
+    let mut checkpointer = Checkpointer::new(&config.checkpoints);
+    let mut diagnostics_log = DiagnosticsLog::for_config(&config).unwrap_or_else(|e| {
+        eprintln!("{}: {e}", config.output.display());
+        None
+    });
+
+    let mut render_bool: bool = true;
+    event_loop.run(move |event, _, control_flow| {
+        match event {
+            // Handle window resize
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } => {
+                context.resize(size);
+                window.request_redraw();
+            }
+
+            // Save (F5) or restore (F9) a snapshot
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state: ElementState::Pressed,
+                                virtual_keycode:
+                                    Some(key @ (VirtualKeyCode::F5 | VirtualKeyCode::F9)),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => {
+                let path = config.dump_path();
+                let restored = Snapshot::handle_key(
+                    key,
+                    path,
+                    &mut simulation,
+                    &bodies,
+                    step,
+                    time,
+                    &parameters,
+                );
+                if let Some(snapshot) = restored {
+                    let snapshot = config.with_command_line_parameters(snapshot);
+                    let camera = mem::take(&mut renderer.camera);
+                    (simulation, renderer, pos_buffer) = load(&context, &snapshot);
+                    renderer.camera = camera;
+                    (step, time, parameters) = (snapshot.step, snapshot.time, snapshot.parameters);
+                    bodies = snapshot.bodies;
+                    if let Some(log) = &mut diagnostics_log {
+                        log.reset_baseline();
+                    }
+                }
+            }
+
+            // Handle camera control inputs
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                virtual_keycode: Some(vkc),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => renderer.camera.handle_key(vkc),
+
+            // Update simulation
+            Event::MainEventsCleared => {
+                let dt = parameters.next_time_step(&mut simulation);
+                simulation.step(dt);
+                step += 1;
+                time += dt as f64;
+                window.set_title(&format!("step {step}, t = {time:.4}, dt = {dt:.4e}"));
+
+                if let Some(log) = &mut diagnostics_log {
+                    match log.record(&mut simulation, &bodies.masses, &parameters, step, time) {
+                        Ok(Some(diagnostics)) => eprintln!("step {step}: {diagnostics}"),
+                        Ok(None) => {}
+                        Err(e) => eprintln!("diagnostics at step {step} failed: {e}"),
+                    }
+                }
+                let saved =
+                    checkpointer.save_if_due(&mut simulation, &bodies, step, time, &parameters);
+                if let Err(e) = saved {
+                    eprintln!("checkpoint at step {step} failed: {e}");
+                }
+
+                // Copy host positions to the GPU buffer
+                if let (Positions::Host(positions), Some(pos_buffer)) =
+                    (simulation.current_positions(), &pos_buffer)
+                {
+                    write_buffer(&context.queue, pos_buffer, positions);
+                }
+
+                // Alternate rendering every other frame
+                if render_bool {
+                    window.request_redraw();
+                }
+                render_bool = !render_bool;
+            }
+
+            // Render (trace.wgsl)
+            Event::RedrawRequested(_) => {
+                let frame = context.surface.get_current_texture().unwrap();
+                let view = frame.texture.create_view(&TextureViewDescriptor::default());
+                let pos_buffer = match simulation.current_positions() {
+                    Positions::Device(pos_buffer) => pos_buffer,
+                    Positions::Host(_) => pos_buffer.as_ref().unwrap(),
+                };
+                renderer.render(&context.device, &context.queue, &view, pos_buffer);
+                frame.present();
+            }
+
+            // Handle window exit
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => *control_flow = ControlFlow::Exit,
+
+            _ => {}
+        }
+    });
+}
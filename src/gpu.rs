@@ -0,0 +1,1072 @@
+use crate::diagnostics::Moments;
+use crate::frontend::{Backend, Positions};
+use crate::gpu_reduce::Reduction;
+use crate::integrator::{BlockSteps, Integrator, Op};
+use crate::softening::SofteningKernel;
+use crate::{Bodies, Parameters, Simulation};
+use encase::internal::{ReadFrom, WriteInto};
+use encase::{ShaderSize, ShaderType, StorageBuffer, UniformBuffer};
+use glam::Vec3;
+use std::borrow::Cow;
+use std::sync::Arc;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+pub(crate) const WG_SIZE: usize = 64;
+pub(crate) const STATIC_GROUP: u32 = 0;
+pub(crate) const KINEMATICS_GROUP: u32 = 1;
+pub(crate) const SCRATCH_GROUP: u32 = 2;
+pub(crate) const MASS_BINDING: u32 = 0;
+pub(crate) const PARAMS_BINDING: u32 = 1;
+pub(crate) const STAGE_BINDING: u32 = 2;
+pub(crate) const SOFTENING_BINDING: u32 = 3;
+pub(crate) const POS_BINDING: u32 = 0; //bindings, not the bind groups
+pub(crate) const VEL_BINDING: u32 = 1; //bindings, not the bind groups
+pub(crate) const ACC_BINDING: u32 = 2; //bindings, not the bind groups
+pub(crate) const JERK_BINDING: u32 = 3; //bindings, not the bind groups
+pub(crate) const SAVED_BINDING: u32 = 0; //bindings, not the bind groups
+pub(crate) const BLOCKS_BINDING: u32 = 1; //bindings, not the bind groups
+pub(crate) const DISPATCH_BINDING: u32 = 2; //bindings, not the bind groups
+/// Storage buffers bound at once by the Barnes-Hut force kernels, one more than wgpu allows by
+/// default.
+const MAX_STORAGE_BUFFERS: u32 = 9;
+
+/// Direct-sum O(N^2) simulation, evaluated by `nbody.wgsl`.
+pub struct GpuSimulation {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    n_bodies: usize,
+
+    parameters: Parameters,
+    params_buffer: Buffer,
+    static_bind_group: BindGroup,
+    kinematics: Kinematics,
+    scratch: Scratch,
+    readback_buffer: Buffer,
+    reduction: Reduction,
+
+    stages: Stages,
+}
+
+impl GpuSimulation {
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        bodies: &Bodies,
+        parameters: &Parameters,
+    ) -> Self {
+        let n_bodies = bodies.len();
+
+        // Setup GPU buffers
+        let mass_buffer = create_buffer_init(
+            &device,
+            "mass_buffer",
+            &bodies.masses,
+            BufferUsages::STORAGE,
+        );
+        let params_buffer = create_params_buffer(&device);
+        write_params(&queue, &params_buffer, parameters, parameters.time_step);
+        let (stage_buffer, stage_stride) = create_stage_buffer(&device, parameters.integrator);
+        // Uniform lengths stay as they are; adaptive ones are computed before every force
+        let softening_buffer = create_buffer_init(
+            &device,
+            "softening_buffer",
+            &vec![parameters.softening; bodies.len().max(1)],
+            BufferUsages::STORAGE,
+        );
+        let static_bind_group_layout = create_static_bind_group_layout(&device);
+        let static_bind_group = create_static_bind_group(
+            &device,
+            &static_bind_group_layout,
+            &mass_buffer,
+            &params_buffer,
+            &stage_buffer,
+            &softening_buffer,
+        );
+
+        let kinematics_bind_group_layout = create_kinematics_bind_group_layout(&device);
+        let kinematics = Kinematics::new(&device, &kinematics_bind_group_layout, bodies);
+        let scratch_bind_group_layout = create_scratch_bind_group_layout(&device);
+        let dispatch_bind_group_layout = create_dispatch_bind_group_layout(&device);
+        let scratch = Scratch::new(
+            &device,
+            &scratch_bind_group_layout,
+            &dispatch_bind_group_layout,
+            n_bodies,
+        );
+        let readback_buffer = create_readback_buffer(&device, &kinematics.pos_buffer);
+        let reduction = Reduction::new(
+            &device,
+            &static_bind_group_layout,
+            &kinematics_bind_group_layout,
+            bodies.len(),
+        );
+
+        // Compile nbody pipelines/shader
+        let nbody_shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("nbody_shader"),
+            source: ShaderSource::Wgsl(Cow::Owned(shader_source(include_str!("nbody.wgsl")))),
+        });
+        let nbody_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("nbody_pipeline_layout"),
+            bind_group_layouts: &[
+                &static_bind_group_layout,
+                &kinematics_bind_group_layout,
+                &scratch_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let dispatch_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("dispatch_pipeline_layout"),
+            bind_group_layouts: &[
+                &static_bind_group_layout,
+                &kinematics_bind_group_layout,
+                &dispatch_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let stages = Stages::new(
+            &device,
+            &nbody_shader,
+            &nbody_pipeline_layout,
+            &nbody_pipeline_layout,
+            &dispatch_pipeline_layout,
+            parameters,
+            stage_stride,
+            n_bodies,
+        );
+
+        let simulation = Self {
+            device,
+            queue,
+            n_bodies,
+            parameters: *parameters,
+            params_buffer,
+            static_bind_group,
+            kinematics,
+            scratch,
+            readback_buffer,
+            reduction,
+            stages,
+        };
+
+        let mut cmd_encoder = simulation.create_command_encoder();
+        {
+            let mut pass = simulation.begin_pass(&mut cmd_encoder);
+            let stages = &simulation.stages;
+            stages.record(&mut pass, &simulation.static_bind_group, 0, Op::Force);
+            if BlockSteps::new(parameters).is_some() {
+                stages.record_assign_rungs(&mut pass, &simulation.static_bind_group);
+            }
+        }
+        simulation.queue.submit(Some(cmd_encoder.finish()));
+        simulation
+    }
+
+    /// Buffer holding the most recent positions, for rendering without a CPU round trip.
+    pub fn positions_buffer(&self) -> &Buffer {
+        &self.kinematics.pos_buffer
+    }
+
+    fn create_command_encoder(&self) -> CommandEncoder {
+        self.device
+            .create_command_encoder(&CommandEncoderDescriptor {
+                label: Some("nbody_step_cmd_encoder"),
+            })
+    }
+
+    /// Start a compute pass with every bind group but the static one set.
+    fn begin_pass<'a>(&'a self, cmd_encoder: &'a mut CommandEncoder) -> ComputePass<'a> {
+        let mut pass = cmd_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("nbody_step_pass"),
+        });
+        pass.set_bind_group(KINEMATICS_GROUP, &self.kinematics.bind_group, &[]);
+        pass.set_bind_group(SCRATCH_GROUP, &self.scratch.bind_group, &[]);
+        pass
+    }
+}
+
+impl Simulation for GpuSimulation {
+    fn step(&mut self, dt: f32) {
+        write_params(&self.queue, &self.params_buffer, &self.parameters, dt);
+        let blocks = BlockSteps::new(&self.parameters);
+        if blocks.is_some() {
+            self.scratch.start_block_step(&self.queue);
+        }
+
+        // Queue nbody sim jobs, all in a single pass
+        let mut cmd_encoder = self.create_command_encoder();
+        {
+            let mut pass = self.begin_pass(&mut cmd_encoder);
+            let static_bind_group = &self.static_bind_group;
+            match blocks {
+                Some(blocks) => {
+                    for _ in 0..blocks.ticks() {
+                        self.stages.record_block_open(&mut pass, static_bind_group);
+                        let scratch = &self.scratch;
+                        self.stages
+                            .record_block_close(&mut pass, static_bind_group, scratch);
+                    }
+                }
+                None => {
+                    let ops = self.parameters.integrator.ops();
+                    for (index, op) in ops.iter().enumerate() {
+                        self.stages.record(&mut pass, static_bind_group, index, *op);
+                    }
+                }
+            }
+        }
+        self.queue.submit(Some(cmd_encoder.finish()));
+    }
+
+    fn n_bodies(&self) -> usize {
+        self.n_bodies
+    }
+
+    fn positions(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.pos_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn velocities(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.vel_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn accelerations(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.acc_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn jerks(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.jerk_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn rungs(&mut self) -> Vec<u32> {
+        self.scratch
+            .read_rungs(&self.device, &self.queue, &self.readback_buffer)
+    }
+
+    fn moments(&mut self) -> Moments {
+        let kinematics = &self.kinematics.bind_group;
+        let static_bind_group = &self.static_bind_group;
+        self.reduction
+            .moments(&self.device, &self.queue, static_bind_group, kinematics)
+    }
+
+    fn set_accelerations(&mut self, accelerations: &[Vec3], jerks: &[Vec3]) {
+        write_buffer(&self.queue, &self.kinematics.acc_buffer, accelerations);
+        write_buffer(&self.queue, &self.kinematics.jerk_buffer, jerks);
+    }
+
+    fn set_rungs(&mut self, rungs: &[u32]) {
+        self.scratch
+            .write_rungs(&self.queue, rungs, self.parameters.max_rung);
+    }
+}
+
+/// Request a device from an adapter that doesn't need to present to a surface.
+pub async fn request_headless_device() -> (Arc<Device>, Arc<Queue>) {
+    let instance = Instance::new(Backends::all());
+    let adapter = instance
+        .request_adapter(&RequestAdapterOptions {
+            compatible_surface: None,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let (device, queue) = adapter
+        .request_device(&device_descriptor(&adapter), None)
+        .await
+        .unwrap();
+    (Arc::new(device), Arc::new(queue))
+}
+
+/// Device with the limits the simulation kernels need, and buffers as large as `adapter`
+/// allows, which the GPU octree of a large N needs.
+pub fn device_descriptor(adapter: &Adapter) -> DeviceDescriptor<'static> {
+    let supported = adapter.limits();
+    DeviceDescriptor {
+        limits: Limits {
+            max_storage_buffers_per_shader_stage: MAX_STORAGE_BUFFERS,
+            max_storage_buffer_binding_size: supported.max_storage_buffer_binding_size,
+            max_buffer_size: supported.max_buffer_size,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Simulation parameters passed to the compute shaders as a uniform.
+#[derive(ShaderType, Default)]
+pub(crate) struct Params {
+    time_step: f32,
+    g: f32,
+    softening: f32,
+    theta: f32,
+    eta: f32,
+    max_rung: u32,
+    force_model: u32,
+    softening_kernel: u32,
+    quadrupole: u32,
+}
+
+impl Params {
+    pub(crate) fn new(parameters: &Parameters, time_step: f32) -> Self {
+        Self {
+            time_step,
+            g: parameters.g,
+            softening: parameters.softening,
+            theta: parameters.theta,
+            eta: parameters.eta,
+            max_rung: parameters.max_rung,
+            force_model: parameters.force_model as u32,
+            softening_kernel: parameters.softening_kernel as u32,
+            quadrupole: parameters.quadrupole as u32,
+        }
+    }
+}
+
+/// Coefficients of one `Op`, read by the `integrate.wgsl` kernels at that op's offset into the
+/// stage buffer.
+#[derive(ShaderType, Default)]
+pub(crate) struct Stage {
+    coefficient: f32,
+    weight: f32,
+}
+
+impl Stage {
+    fn new(op: Op) -> Self {
+        let (coefficient, weight) = match op {
+            Op::Kick(c) | Op::Drift(c) => (c, 0.0),
+            Op::Rk4Stage { weight, next } => (next, weight),
+            Op::Rk4Finish { weight } => (0.0, weight),
+            _ => (0.0, 0.0),
+        };
+        Self {
+            coefficient,
+            weight,
+        }
+    }
+}
+
+/// Per-body scratch space of the integrators, laid out as `Saved` in `integrate.wgsl`.
+#[derive(ShaderType)]
+struct Saved {
+    position: Vec3,
+    velocity: Vec3,
+    acceleration: Vec3,
+    jerk: Vec3,
+    position_sum: Vec3,
+    velocity_sum: Vec3,
+}
+
+/// Positions, velocities, accelerations and jerks of every body.
+pub(crate) struct Kinematics {
+    pub(crate) pos_buffer: Buffer,
+    pub(crate) vel_buffer: Buffer,
+    pub(crate) acc_buffer: Buffer,
+    pub(crate) jerk_buffer: Buffer,
+    pub(crate) bind_group: BindGroup,
+}
+
+impl Kinematics {
+    pub(crate) fn new(device: &Device, layout: &BindGroupLayout, bodies: &Bodies) -> Self {
+        let usage = BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST;
+        let zeros = vec![Vec3::ZERO; bodies.len()];
+        let pos_buffer = create_buffer_init(device, "pos_buffer", &bodies.positions, usage);
+        let vel_buffer = create_buffer_init(device, "vel_buffer", &bodies.velocities, usage);
+        let acc_buffer = create_buffer_init(device, "acc_buffer", &zeros, usage);
+        let jerk_buffer = create_buffer_init(device, "jerk_buffer", &zeros, usage);
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("kinematics_bind_group"),
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: POS_BINDING,
+                    resource: pos_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: VEL_BINDING,
+                    resource: vel_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: ACC_BINDING,
+                    resource: acc_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: JERK_BINDING,
+                    resource: jerk_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        Self {
+            pos_buffer,
+            vel_buffer,
+            acc_buffer,
+            jerk_buffer,
+            bind_group,
+        }
+    }
+}
+
+/// Compute pipelines for every `Op` of an integrator, or for the ticks of block steps: the
+/// kernels of `integrate.wgsl`, plus the backend's own force kernels.
+///
+/// The kernels of a block step tick that only see to the active bodies are dispatched
+/// indirectly, over as many workgroups as `block_compact` finds them to need.
+pub(crate) struct Stages {
+    integrator: Integrator,
+    adaptive_softening: bool,
+    pipelines: Vec<(&'static str, ComputePipeline)>,
+    stride: u32,
+    n_workgroups: u32,
+}
+
+/// Entry points of `integrate.wgsl` used by block steps, in addition to the force kernels.
+const BLOCK_ENTRY_POINTS: [&str; 5] = [
+    "assign_rungs",
+    "block_open",
+    "block_compact",
+    "block_close",
+    "block_advance",
+];
+
+/// Entry points of `integrate.wgsl` that count the active bodies' workgroups, and so bind the
+/// dispatch group in place of the scratch one.
+const DISPATCH_ENTRY_POINTS: [&str; 2] = ["block_compact", "block_advance"];
+
+/// Entry points that evaluate forces, and so may need the backend's extra bind groups.
+const FORCE_ENTRY_POINTS: [&str; 3] = [
+    "compute_accelerations",
+    "compute_accelerations_and_jerks",
+    "compute_active_accelerations",
+];
+
+impl Stages {
+    /// Pipelines from `shader`, which must be built with `shader_source`. Force kernels use
+    /// `force_layout`, which may add bind groups to `integrate_layout`, and the block step kernels
+    /// that count the active bodies use `dispatch_layout`, which binds the dispatch group instead
+    /// of the scratch one.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        device: &Device,
+        shader: &ShaderModule,
+        integrate_layout: &PipelineLayout,
+        force_layout: &PipelineLayout,
+        dispatch_layout: &PipelineLayout,
+        parameters: &Parameters,
+        stride: u32,
+        n_bodies: usize,
+    ) -> Self {
+        let integrator = parameters.integrator;
+        let blocks = BlockSteps::new(parameters).is_some();
+        let adaptive_softening = parameters.softening_kernel == SofteningKernel::Adaptive;
+        let mut entry_points: Vec<&'static str> = if blocks {
+            BLOCK_ENTRY_POINTS
+                .into_iter()
+                .chain(["compute_active_accelerations"])
+                .collect()
+        } else {
+            integrator
+                .ops()
+                .iter()
+                .map(|op| entry_point(*op, integrator))
+                .collect()
+        };
+        // The initial accelerations are always needed
+        entry_points.push(entry_point(Op::Force, integrator));
+        if adaptive_softening {
+            entry_points.push("compute_softening_lengths");
+            if blocks {
+                entry_points.push("compute_active_softening_lengths");
+            }
+        }
+        entry_points.sort_unstable();
+        entry_points.dedup();
+
+        let pipelines = entry_points
+            .into_iter()
+            .map(|entry_point| {
+                let layout = if FORCE_ENTRY_POINTS.contains(&entry_point) {
+                    force_layout
+                } else if DISPATCH_ENTRY_POINTS.contains(&entry_point) {
+                    dispatch_layout
+                } else {
+                    integrate_layout
+                };
+                let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+                    label: Some(entry_point),
+                    layout: Some(layout),
+                    module: shader,
+                    entry_point,
+                });
+                (entry_point, pipeline)
+            })
+            .collect();
+        Self {
+            integrator,
+            adaptive_softening,
+            pipelines,
+            stride,
+            n_workgroups: n_workgroups(n_bodies),
+        }
+    }
+
+    /// Record `op`, the `index`th of the integrator, into `pass`. Every bind group other than the
+    /// static one must already be set.
+    pub(crate) fn record<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        static_bind_group: &'a BindGroup,
+        index: usize,
+        op: Op,
+    ) {
+        if op == Op::Force {
+            self.record_softening_lengths(pass, static_bind_group);
+        }
+        let entry_point = entry_point(op, self.integrator);
+        let n_workgroups = self.n_workgroups;
+        self.dispatch(pass, static_bind_group, index, entry_point, n_workgroups);
+    }
+
+    /// Record putting every body on the rung its acceleration calls for.
+    pub(crate) fn record_assign_rungs<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        static_bind_group: &'a BindGroup,
+    ) {
+        let n_workgroups = self.n_workgroups;
+        self.dispatch(pass, static_bind_group, 0, "assign_rungs", n_workgroups);
+    }
+
+    /// Record updating the softening lengths ahead of a force evaluation, if they're adaptive.
+    fn record_softening_lengths<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        static_bind_group: &'a BindGroup,
+    ) {
+        if self.adaptive_softening {
+            let entry_point = "compute_softening_lengths";
+            self.dispatch(pass, static_bind_group, 0, entry_point, self.n_workgroups);
+        }
+    }
+
+    /// Record the first half of a block step tick, up to where the forces are needed: the
+    /// opening kicks and the drift.
+    pub(crate) fn record_block_open<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        static_bind_group: &'a BindGroup,
+    ) {
+        self.dispatch(pass, static_bind_group, 0, "block_open", self.n_workgroups);
+    }
+
+    /// Record the rest of a block step tick: gathering the active bodies, evaluating their forces
+    /// and closing their steps, with only as many workgroups as there are active bodies. The
+    /// scratch group is bound again afterwards.
+    pub(crate) fn record_block_close<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        static_bind_group: &'a BindGroup,
+        scratch: &'a Scratch,
+    ) {
+        pass.set_bind_group(SCRATCH_GROUP, &scratch.dispatch_bind_group, &[]);
+        let n_workgroups = self.n_workgroups;
+        self.dispatch(pass, static_bind_group, 0, "block_compact", n_workgroups);
+        pass.set_bind_group(SCRATCH_GROUP, &scratch.bind_group, &[]);
+        let mut entry_points = vec!["compute_active_accelerations", "block_close"];
+        if self.adaptive_softening {
+            entry_points.insert(0, "compute_active_softening_lengths");
+        }
+        for entry_point in entry_points {
+            self.set_pipeline(pass, static_bind_group, 0, entry_point);
+            pass.dispatch_workgroups_indirect(&scratch.dispatch_buffer, 0);
+        }
+        pass.set_bind_group(SCRATCH_GROUP, &scratch.dispatch_bind_group, &[]);
+        self.dispatch(pass, static_bind_group, 0, "block_advance", 1);
+        pass.set_bind_group(SCRATCH_GROUP, &scratch.bind_group, &[]);
+    }
+
+    fn dispatch<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        static_bind_group: &'a BindGroup,
+        stage_index: usize,
+        entry_point: &str,
+        n_workgroups: u32,
+    ) {
+        self.set_pipeline(pass, static_bind_group, stage_index, entry_point);
+        pass.dispatch_workgroups(n_workgroups, 1, 1);
+    }
+
+    fn set_pipeline<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        static_bind_group: &'a BindGroup,
+        stage_index: usize,
+        entry_point: &str,
+    ) {
+        let (_, pipeline) = self
+            .pipelines
+            .iter()
+            .find(|(name, _)| *name == entry_point)
+            .unwrap();
+        pass.set_pipeline(pipeline);
+        let offset = stage_index as u32 * self.stride;
+        pass.set_bind_group(STATIC_GROUP, static_bind_group, &[offset]);
+    }
+}
+
+/// Block step rung of one body, and the index of one of the bodies active in the current tick;
+/// laid out as `Slot` in `integrate.wgsl`.
+#[derive(ShaderType, Clone, Copy, Default)]
+struct Slot {
+    rung: u32,
+    active_body: u32,
+}
+
+/// Progress through a block step, laid out as `Blocks` in `integrate.wgsl`.
+#[derive(ShaderType, Default)]
+struct Blocks {
+    tick: u32,
+    n_active: u32,
+    #[size(runtime)]
+    slots: Vec<Slot>,
+}
+
+/// Workgroups of an indirect dispatch, laid out as `Dispatch` in `integrate.wgsl`.
+#[derive(ShaderType)]
+struct DispatchArgs {
+    x: u32,
+    y: u32,
+    z: u32,
+}
+
+/// Per-body scratch space of the integrators and block steps, and the indirect dispatch
+/// arguments of the block step kernels that see to the active bodies.
+pub(crate) struct Scratch {
+    blocks_buffer: Buffer,
+    dispatch_buffer: Buffer,
+    pub(crate) bind_group: BindGroup,
+    /// Binds the blocks alongside the dispatch arguments, for the kernels that count them
+    dispatch_bind_group: BindGroup,
+}
+
+impl Scratch {
+    pub(crate) fn new(
+        device: &Device,
+        layout: &BindGroupLayout,
+        dispatch_layout: &BindGroupLayout,
+        n_bodies: usize,
+    ) -> Self {
+        let saved_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("saved_buffer"),
+            size: n_bodies.max(1) as u64 * Saved::SHADER_SIZE.get(),
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let blocks = Blocks {
+            slots: vec![Slot::default(); n_bodies.max(1)],
+            ..Default::default()
+        };
+        let usage = BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST;
+        let blocks_buffer = create_buffer_init(device, "blocks_buffer", &blocks, usage);
+        let dispatch = DispatchArgs { x: 0, y: 1, z: 1 };
+        let usage = BufferUsages::STORAGE | BufferUsages::INDIRECT | BufferUsages::COPY_DST;
+        let dispatch_buffer = create_buffer_init(device, "dispatch_buffer", &dispatch, usage);
+
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("scratch_bind_group"),
+            layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: SAVED_BINDING,
+                    resource: saved_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: BLOCKS_BINDING,
+                    resource: blocks_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let dispatch_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("dispatch_bind_group"),
+            layout: dispatch_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: BLOCKS_BINDING,
+                    resource: blocks_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: DISPATCH_BINDING,
+                    resource: dispatch_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        Self {
+            blocks_buffer,
+            dispatch_buffer,
+            bind_group,
+            dispatch_bind_group,
+        }
+    }
+
+    /// Rewind to the first tick, ahead of the next submission.
+    pub(crate) fn start_block_step(&self, queue: &Queue) {
+        queue.write_buffer(&self.blocks_buffer, 0, &[0; 8]);
+        queue.write_buffer(&self.dispatch_buffer, 0, &[0; 4]);
+    }
+
+    pub(crate) fn read_rungs(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        readback_buffer: &Buffer,
+    ) -> Vec<u32> {
+        let blocks: Blocks = read_buffer(device, queue, &self.blocks_buffer, readback_buffer);
+        blocks.slots.iter().map(|slot| slot.rung).collect()
+    }
+
+    /// Put the bodies on `rungs`, kept within `max_rung`.
+    pub(crate) fn write_rungs(&self, queue: &Queue, rungs: &[u32], max_rung: u32) {
+        let blocks = Blocks {
+            slots: rungs
+                .iter()
+                .map(|rung| Slot {
+                    rung: (*rung).min(max_rung),
+                    active_body: 0,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut data = StorageBuffer::new(Vec::new());
+        data.write(&blocks).unwrap();
+        queue.write_buffer(&self.blocks_buffer, 0, &data.into_inner());
+    }
+}
+
+pub(crate) fn create_buffer_init<T: ShaderType + WriteInto>(
+    device: &Device,
+    label: &str,
+    data: &T,
+    usage: BufferUsages,
+) -> Buffer {
+    let mut buffer = StorageBuffer::new(Vec::new());
+    buffer.write(data).unwrap();
+    let buffer = buffer.into_inner();
+    device.create_buffer_init(&BufferInitDescriptor {
+        label: Some(label),
+        contents: &buffer,
+        usage, //inherently mapped at creation, as it creates and copies the data in one fn call
+    })
+}
+
+pub(crate) fn create_params_buffer(device: &Device) -> Buffer {
+    let mut params_buffer = UniformBuffer::new(Vec::new());
+    params_buffer.write(&Params::default()).unwrap();
+    let params_buffer = params_buffer.into_inner();
+    device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("params_buffer"),
+        contents: &params_buffer,
+        usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+    })
+}
+
+/// Buffer of one `Stage` per op of `integrator`, each at a multiple of the returned stride so it
+/// can be bound with a dynamic offset.
+pub(crate) fn create_stage_buffer(device: &Device, integrator: Integrator) -> (Buffer, u32) {
+    let stride = device.limits().min_uniform_buffer_offset_alignment;
+    let ops = integrator.ops();
+    let mut contents = vec![0; ops.len() * stride as usize];
+    for (index, op) in ops.iter().enumerate() {
+        let mut stage = UniformBuffer::new(Vec::new());
+        stage.write(&Stage::new(*op)).unwrap();
+        let stage = stage.into_inner();
+        let offset = index * stride as usize;
+        contents[offset..offset + stage.len()].copy_from_slice(&stage);
+    }
+    let buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("stage_buffer"),
+        contents: &contents,
+        usage: BufferUsages::UNIFORM,
+    });
+    (buffer, stride)
+}
+
+/// WGSL entry point that carries out `op`.
+fn entry_point(op: Op, integrator: Integrator) -> &'static str {
+    match op {
+        Op::Kick(_) => "kick",
+        Op::Drift(_) => "drift",
+        Op::Force if integrator.needs_jerks() => "compute_accelerations_and_jerks",
+        Op::Force => "compute_accelerations",
+        Op::Save => "save",
+        Op::Rk4Stage { .. } => "rk4_stage",
+        Op::Rk4Finish { .. } => "rk4_finish",
+        Op::HermitePredict => "hermite_predict",
+        Op::HermiteCorrect => "hermite_correct",
+    }
+}
+
+/// A backend's force kernels, between the shared declarations, softening kernels and pair force
+/// they use and the integrator kernels that use them.
+pub(crate) fn shader_source(forces: &str) -> String {
+    format!(
+        "{}\n{}\n{}\n{forces}\n{}",
+        include_str!("bodies.wgsl"),
+        include_str!("softening.wgsl"),
+        include_str!("pair.wgsl"),
+        include_str!("integrate.wgsl")
+    )
+}
+
+pub(crate) fn write_params(
+    queue: &Queue,
+    params_buffer: &Buffer,
+    parameters: &Parameters,
+    time_step: f32,
+) {
+    let mut params = UniformBuffer::new(Vec::new());
+    params.write(&Params::new(parameters, time_step)).unwrap();
+    queue.write_buffer(params_buffer, 0, &params.into_inner());
+}
+
+pub(crate) fn create_readback_buffer(device: &Device, like: &Buffer) -> Buffer {
+    device.create_buffer(&BufferDescriptor {
+        label: Some("readback_buffer"),
+        size: like.size(),
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// Copy `buffer` into `readback_buffer` and block until it can be read on the CPU.
+pub(crate) fn read_buffer<T: ShaderType + ReadFrom + Default>(
+    device: &Device,
+    queue: &Queue,
+    buffer: &Buffer,
+    readback_buffer: &Buffer,
+) -> T {
+    let mut readback_cmd_encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("readback_cmd_encoder"),
+    });
+    readback_cmd_encoder.copy_buffer_to_buffer(buffer, 0, readback_buffer, 0, buffer.size());
+    queue.submit(Some(readback_cmd_encoder.finish()));
+
+    let mut values = T::default();
+    let slice = readback_buffer.slice(..);
+    let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+    slice.map_async(MapMode::Read, move |v| sender.send(v).unwrap());
+    device.poll(Maintain::Wait);
+    pollster::block_on(receiver.receive());
+    let data = slice.get_mapped_range();
+    let buf = StorageBuffer::new(&data[..buffer.size() as usize]);
+    buf.read(&mut values).unwrap();
+    drop(data);
+    readback_buffer.unmap();
+    values
+}
+
+/// Overwrite the start of `buffer` with `values`.
+pub(crate) fn write_buffer(queue: &Queue, buffer: &Buffer, values: &[Vec3]) {
+    let mut data = StorageBuffer::new(Vec::new());
+    data.write(&values.to_vec()).unwrap();
+    queue.write_buffer(buffer, 0, &data.into_inner());
+}
+
+pub(crate) fn n_workgroups(n_bodies: usize) -> u32 {
+    (((n_bodies as f32) / (WG_SIZE as f32)).ceil()) as u32
+}
+
+pub(crate) fn create_static_bind_group_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("static_bind_group_layout"),
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: MASS_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: PARAMS_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: STAGE_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: Some(Stage::min_size()),
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: SOFTENING_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+pub(crate) fn create_static_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    mass_buffer: &Buffer,
+    params_buffer: &Buffer,
+    stage_buffer: &Buffer,
+    softening_buffer: &Buffer,
+) -> BindGroup {
+    device.create_bind_group(&BindGroupDescriptor {
+        label: Some("static_bind_group"),
+        layout,
+        entries: &[
+            BindGroupEntry {
+                binding: MASS_BINDING,
+                resource: mass_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: PARAMS_BINDING,
+                resource: params_buffer.as_entire_binding(),
+            },
+            BindGroupEntry {
+                binding: STAGE_BINDING,
+                resource: BindingResource::Buffer(BufferBinding {
+                    buffer: stage_buffer,
+                    offset: 0,
+                    size: Some(Stage::min_size()),
+                }),
+            },
+            BindGroupEntry {
+                binding: SOFTENING_BINDING,
+                resource: softening_buffer.as_entire_binding(),
+            },
+        ],
+    })
+}
+
+pub(crate) fn create_kinematics_bind_group_layout(device: &Device) -> BindGroupLayout {
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("kinematics_bind_group_layout"),
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: POS_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: VEL_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: ACC_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: JERK_BINDING,
+                visibility: ShaderStages::COMPUTE,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Storage { read_only: false },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+    })
+}
+
+pub(crate) fn create_scratch_bind_group_layout(device: &Device) -> BindGroupLayout {
+    let storage_entry = |binding| BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("scratch_bind_group_layout"),
+        entries: &[storage_entry(SAVED_BINDING), storage_entry(BLOCKS_BINDING)],
+    })
+}
+
+/// Layout of the group bound in place of the scratch one by the kernels that count the active
+/// bodies' workgroups. The dispatch arguments can't be in the scratch group itself, as a buffer
+/// can't be bound for writing by a dispatch that also reads its arguments from it.
+pub(crate) fn create_dispatch_bind_group_layout(device: &Device) -> BindGroupLayout {
+    let storage_entry = |binding| BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only: false },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+        label: Some("dispatch_bind_group_layout"),
+        entries: &[
+            storage_entry(BLOCKS_BINDING),
+            storage_entry(DISPATCH_BINDING),
+        ],
+    })
+}
+
+impl Backend for GpuSimulation {
+    const COMPUTES_JERKS: bool = true;
+
+    fn create(
+        device: impl FnOnce() -> (Arc<Device>, Arc<Queue>),
+        bodies: &Bodies,
+        parameters: &Parameters,
+    ) -> Self {
+        let (device, queue) = device();
+        Self::new(device, queue, bodies, parameters)
+    }
+
+    fn current_positions(&self) -> Positions<'_> {
+        Positions::Device(self.positions_buffer())
+    }
+}
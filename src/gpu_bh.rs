@@ -0,0 +1,369 @@
+use crate::diagnostics::Moments;
+use crate::frontend::{Backend, Positions};
+use crate::gpu::{
+    create_buffer_init, create_dispatch_bind_group_layout, create_kinematics_bind_group_layout,
+    create_params_buffer, create_readback_buffer, create_scratch_bind_group_layout,
+    create_stage_buffer, create_static_bind_group, create_static_bind_group_layout, read_buffer,
+    shader_source, write_buffer, write_params, Kinematics, Scratch, Stages, KINEMATICS_GROUP,
+    SCRATCH_GROUP,
+};
+use crate::gpu_octree::{self, GpuOctree};
+use crate::gpu_reduce::Reduction;
+use crate::integrator::{BlockSteps, Op};
+use crate::{Bodies, Parameters, Simulation};
+use glam::Vec3;
+use std::borrow::Cow;
+use std::process;
+use std::sync::Arc;
+use wgpu::*;
+
+const OCTREE_GROUP: u32 = 3;
+
+/// Barnes-Hut O(N log N) simulation, evaluated by `nbodybh.wgsl`.
+///
+/// The octree is rebuilt on the GPU by `GpuOctree` before every force evaluation, so positions
+/// never leave it between steps. Its nodes carry no velocities, so integrators that need jerks
+/// aren't supported.
+pub struct GpuBhSimulation {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+    masses: Vec<f32>,
+
+    parameters: Parameters,
+    params_buffer: Buffer,
+    static_bind_group: BindGroup,
+    kinematics: Kinematics,
+    scratch: Scratch,
+    readback_buffer: Buffer,
+    reduction: Reduction,
+    octree: GpuOctree,
+    octree_bind_group: BindGroup,
+
+    stages: Stages,
+}
+
+impl GpuBhSimulation {
+    pub fn new(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        bodies: &Bodies,
+        parameters: &Parameters,
+    ) -> Self {
+        let integrator = parameters.integrator;
+        assert!(
+            !integrator.needs_jerks(),
+            "the Barnes-Hut simulation can't compute jerks for {integrator:?}"
+        );
+        let max_bodies = gpu_octree::max_bodies(&device);
+        assert!(
+            bodies.len() <= max_bodies,
+            "the device can only hold the octree of {max_bodies} bodies"
+        );
+
+        // Setup GPU buffers
+        let mass_buffer = create_buffer_init(
+            &device,
+            "mass_buffer",
+            &bodies.masses,
+            BufferUsages::STORAGE,
+        );
+        let params_buffer = create_params_buffer(&device);
+        write_params(&queue, &params_buffer, parameters, parameters.time_step);
+        let (stage_buffer, stage_stride) = create_stage_buffer(&device, integrator);
+        // Uniform lengths stay as they are; adaptive ones are computed before every force
+        let softening_buffer = create_buffer_init(
+            &device,
+            "softening_buffer",
+            &vec![parameters.softening; bodies.len().max(1)],
+            BufferUsages::STORAGE,
+        );
+        let static_bind_group_layout = create_static_bind_group_layout(&device);
+        let static_bind_group = create_static_bind_group(
+            &device,
+            &static_bind_group_layout,
+            &mass_buffer,
+            &params_buffer,
+            &stage_buffer,
+            &softening_buffer,
+        );
+
+        let kinematics_bind_group_layout = create_kinematics_bind_group_layout(&device);
+        let kinematics = Kinematics::new(&device, &kinematics_bind_group_layout, bodies);
+        let scratch_bind_group_layout = create_scratch_bind_group_layout(&device);
+        let dispatch_bind_group_layout = create_dispatch_bind_group_layout(&device);
+        let scratch = Scratch::new(
+            &device,
+            &scratch_bind_group_layout,
+            &dispatch_bind_group_layout,
+            bodies.len(),
+        );
+        let readback_buffer = create_readback_buffer(&device, &kinematics.pos_buffer);
+        let reduction = Reduction::new(
+            &device,
+            &static_bind_group_layout,
+            &kinematics_bind_group_layout,
+            bodies.len(),
+        );
+
+        let octree = GpuOctree::new(
+            &device,
+            &kinematics.pos_buffer,
+            &mass_buffer,
+            bodies.len(),
+            parameters,
+        );
+        let octree_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("octree_bind_group_layout"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+        let octree_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("octree_bind_group"),
+            layout: &octree_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: octree.octree_buffer().as_entire_binding(),
+            }],
+        });
+
+        // Compile nbody pipelines/shader
+        let nbody_shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("nbody_shader"),
+            source: ShaderSource::Wgsl(Cow::Owned(shader_source(&format!(
+                "{}\n{}",
+                include_str!("octree_node.wgsl"),
+                include_str!("nbodybh.wgsl")
+            )))),
+        });
+        let integrate_layouts = [
+            &static_bind_group_layout,
+            &kinematics_bind_group_layout,
+            &scratch_bind_group_layout,
+        ];
+        let integrate_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("integrate_pipeline_layout"),
+            bind_group_layouts: &integrate_layouts,
+            push_constant_ranges: &[],
+        });
+        let nbody_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("nbody_pipeline_layout"),
+            bind_group_layouts: &[
+                &static_bind_group_layout,
+                &kinematics_bind_group_layout,
+                &scratch_bind_group_layout,
+                &octree_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let dispatch_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("dispatch_pipeline_layout"),
+            bind_group_layouts: &[
+                &static_bind_group_layout,
+                &kinematics_bind_group_layout,
+                &dispatch_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let stages = Stages::new(
+            &device,
+            &nbody_shader,
+            &integrate_pipeline_layout,
+            &nbody_pipeline_layout,
+            &dispatch_pipeline_layout,
+            parameters,
+            stage_stride,
+            bodies.len(),
+        );
+
+        let simulation = Self {
+            device,
+            queue,
+            masses: bodies.masses.clone(),
+            parameters: *parameters,
+            params_buffer,
+            static_bind_group,
+            kinematics,
+            scratch,
+            readback_buffer,
+            reduction,
+            octree,
+            octree_bind_group,
+            stages,
+        };
+        simulation.run(0, Op::Force);
+        if BlockSteps::new(parameters).is_some() {
+            simulation.submit(|cmd_encoder| {
+                simulation.record_pass(cmd_encoder, |pass, simulation| {
+                    let static_bind_group = &simulation.static_bind_group;
+                    simulation
+                        .stages
+                        .record_assign_rungs(pass, static_bind_group)
+                });
+            });
+        }
+        simulation
+    }
+
+    /// Buffer holding the most recent positions, for rendering without a CPU round trip.
+    pub fn positions_buffer(&self) -> &Buffer {
+        &self.kinematics.pos_buffer
+    }
+
+    /// Queue `op`, the `index`th of the integrator, rebuilding the octree first if it's a force
+    /// evaluation.
+    fn run(&self, index: usize, op: Op) {
+        self.submit(|cmd_encoder| {
+            if op == Op::Force {
+                self.octree.record(cmd_encoder);
+            }
+            self.record_pass(cmd_encoder, |pass, simulation| {
+                let static_bind_group = &simulation.static_bind_group;
+                simulation.stages.record(pass, static_bind_group, index, op)
+            });
+        });
+    }
+
+    /// Record one tick of a block step, rebuilding the octree from the drifted positions between
+    /// its two passes.
+    fn record_tick(&self, cmd_encoder: &mut CommandEncoder) {
+        self.record_pass(cmd_encoder, |pass, simulation| {
+            let static_bind_group = &simulation.static_bind_group;
+            simulation.stages.record_block_open(pass, static_bind_group)
+        });
+        self.octree.record(cmd_encoder);
+        self.record_pass(cmd_encoder, |pass, simulation| {
+            let (static_bind_group, scratch) = (&simulation.static_bind_group, &simulation.scratch);
+            simulation
+                .stages
+                .record_block_close(pass, static_bind_group, scratch)
+        });
+    }
+
+    /// Submit everything `record` encodes in one go.
+    fn submit(&self, record: impl FnOnce(&mut CommandEncoder)) {
+        let mut nbody_step_cmd_encoder =
+            self.device
+                .create_command_encoder(&CommandEncoderDescriptor {
+                    label: Some("nbody_step_cmd_encoder"),
+                });
+        record(&mut nbody_step_cmd_encoder);
+        self.queue.submit(Some(nbody_step_cmd_encoder.finish()));
+    }
+
+    /// Record a single pass, with every bind group set but the static one, which `record` sets
+    /// through this simulation's `Stages`.
+    fn record_pass(
+        &self,
+        cmd_encoder: &mut CommandEncoder,
+        record: impl for<'a> FnOnce(&mut ComputePass<'a>, &'a Self),
+    ) {
+        let mut nbody_step_pass = cmd_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("nbody_step_pass"),
+        });
+        let kinematics = &self.kinematics.bind_group;
+        nbody_step_pass.set_bind_group(KINEMATICS_GROUP, kinematics, &[]);
+        nbody_step_pass.set_bind_group(SCRATCH_GROUP, &self.scratch.bind_group, &[]);
+        nbody_step_pass.set_bind_group(OCTREE_GROUP, &self.octree_bind_group, &[]);
+        record(&mut nbody_step_pass, self);
+    }
+}
+
+impl Simulation for GpuBhSimulation {
+    fn step(&mut self, dt: f32) {
+        write_params(&self.queue, &self.params_buffer, &self.parameters, dt);
+        if let Some(blocks) = BlockSteps::new(&self.parameters) {
+            self.scratch.start_block_step(&self.queue);
+            //every tick in one submission, as the direct GPU backend does in its single pass
+            self.submit(|cmd_encoder| {
+                for _ in 0..blocks.ticks() {
+                    self.record_tick(cmd_encoder);
+                }
+            });
+            return;
+        }
+        for (index, op) in self.parameters.integrator.ops().iter().enumerate() {
+            self.run(index, *op);
+        }
+    }
+
+    fn n_bodies(&self) -> usize {
+        self.masses.len()
+    }
+
+    fn positions(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.pos_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn velocities(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.vel_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn accelerations(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.acc_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn jerks(&mut self) -> Vec<Vec3> {
+        let buffer = &self.kinematics.jerk_buffer;
+        read_buffer(&self.device, &self.queue, buffer, &self.readback_buffer)
+    }
+
+    fn rungs(&mut self) -> Vec<u32> {
+        self.scratch
+            .read_rungs(&self.device, &self.queue, &self.readback_buffer)
+    }
+
+    fn moments(&mut self) -> Moments {
+        let kinematics = &self.kinematics.bind_group;
+        let static_bind_group = &self.static_bind_group;
+        self.reduction
+            .moments(&self.device, &self.queue, static_bind_group, kinematics)
+    }
+
+    fn set_accelerations(&mut self, accelerations: &[Vec3], jerks: &[Vec3]) {
+        write_buffer(&self.queue, &self.kinematics.acc_buffer, accelerations);
+        write_buffer(&self.queue, &self.kinematics.jerk_buffer, jerks);
+    }
+
+    fn set_rungs(&mut self, rungs: &[u32]) {
+        self.scratch
+            .write_rungs(&self.queue, rungs, self.parameters.max_rung);
+    }
+}
+
+impl Backend for GpuBhSimulation {
+    const COMPUTES_JERKS: bool = false;
+
+    fn create(
+        device: impl FnOnce() -> (Arc<Device>, Arc<Queue>),
+        bodies: &Bodies,
+        parameters: &Parameters,
+    ) -> Self {
+        let (device, queue) = device();
+        let max_bodies = gpu_octree::max_bodies(&device);
+        if bodies.len() > max_bodies {
+            eprintln!(
+                "{} bodies are too many for Barnes-Hut on this device, which can hold the \
+                 octree of {max_bodies}",
+                bodies.len()
+            );
+            process::exit(1);
+        }
+        Self::new(device, queue, bodies, parameters)
+    }
+
+    fn current_positions(&self) -> Positions<'_> {
+        Positions::Device(self.positions_buffer())
+    }
+}
@@ -0,0 +1,342 @@
+use crate::octree_maxdepth::{OctreeNode, TreeShape};
+use crate::Parameters;
+use encase::{ShaderSize, ShaderType, UniformBuffer};
+use std::borrow::Cow;
+use std::num::NonZeroU64;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+
+const TREE_WG_SIZE: usize = 256;
+const TREE_GROUP: u32 = 0;
+const ITEMS_GROUP: u32 = 1;
+/// Digits of 4 bits in the two 30-bit halves of a Morton key.
+const SORT_PASSES: u32 = 15;
+const RADIX: usize = 16;
+
+#[derive(ShaderType)]
+struct TreeParams {
+    quadrupole: u32,
+    leaf_size: u32,
+    max_depth: u32,
+}
+
+/// Settings of one dispatch of `octree_build.wgsl`, laid out as its `Pass`.
+#[derive(ShaderType)]
+struct Pass {
+    index: u32,
+    length: u32,
+}
+
+/// A body's sort key, laid out as `Item` in `octree_build.wgsl`.
+#[derive(ShaderType)]
+struct Item {
+    hi: u32,
+    lo: u32,
+    body: u32,
+}
+
+/// Bytes taken by the octree of `n_bodies`: up to 2n nodes, then the block of n Leaf-Bodies.
+fn octree_size(n_bodies: u64) -> u64 {
+    3 * n_bodies * OctreeNode::SHADER_SIZE.get()
+}
+
+/// Most bodies whose octree `device` can hold in the single buffer `nbodybh.wgsl` binds.
+pub(crate) fn max_bodies(device: &Device) -> usize {
+    let limits = device.limits();
+    let max_size = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+    (max_size / octree_size(1)) as usize
+}
+
+/// Builds the Barnes-Hut octree from the positions with `octree_build.wgsl`, so they never
+/// leave the GPU.
+///
+/// The root is sized to fit the bodies and they're radix sorted by Morton key, then every node
+/// is emitted and linked to its parent in parallel and summed a depth at a time, quadrupole
+/// moments included if asked for. The tree has the layout of `OctreeNode`, but with the nodes
+/// that have a single child left out, and every body also in a block of Leaf-Body nodes from
+/// node 2n on, which the Leaf-Lists point into. The nodes come in depth-first order, with their
+/// ropes threaded through that block too, so `nbodybh.wgsl` walks it without a stack.
+pub(crate) struct GpuOctree {
+    pipelines: Vec<(&'static str, ComputePipeline)>,
+    tree_bind_group: BindGroup,
+    /// Sorting from the first items buffer into the second, and back
+    items_bind_groups: [BindGroup; 2],
+    octree_buffer: Buffer,
+    stride: u32,
+    n_bodies: usize,
+    max_depth: u32,
+}
+
+impl GpuOctree {
+    pub(crate) fn new(
+        device: &Device,
+        pos_buffer: &Buffer,
+        mass_buffer: &Buffer,
+        n_bodies: usize,
+        parameters: &Parameters,
+    ) -> Self {
+        let storage_buffer = |label, size: u64| {
+            device.create_buffer(&BufferDescriptor {
+                label: Some(label),
+                size: size.max(1) * 4,
+                usage: BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let n = n_bodies.max(1) as u64;
+        let n_sort_workgroups = n_bodies.div_ceil(TREE_WG_SIZE) as u64;
+        let octree_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("octree_buffer"),
+            size: octree_size(n),
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let heads_buffer = storage_buffer("heads_buffer", n);
+        let root_buffer = storage_buffer("root_buffer", 4);
+        let counts_buffer =
+            storage_buffer("counts_buffer", n.max(RADIX as u64 * n_sort_workgroups));
+        let items_size = n * Item::SHADER_SIZE.get() / 4;
+        let items_buffers = [
+            storage_buffer("items_buffer_a", items_size),
+            storage_buffer("items_buffer_b", items_size),
+        ];
+
+        let mut tree_params = UniformBuffer::new(Vec::new());
+        let shape = TreeShape::new(parameters);
+        let params = TreeParams {
+            quadrupole: parameters.quadrupole as u32,
+            leaf_size: shape.leaf_size,
+            max_depth: shape.max_depth,
+        };
+        tree_params.write(&params).unwrap();
+        let tree_params_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("tree_params_buffer"),
+            contents: &tree_params.into_inner(),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        // One pass per sort digit, one for the heads' scan, and one per depth to sum
+        let stride = device.limits().min_uniform_buffer_offset_alignment;
+        let sort_passes = (0..SORT_PASSES).map(|index| Pass {
+            index,
+            length: RADIX as u32 * n_sort_workgroups as u32,
+        });
+        let heads_pass = Pass {
+            index: 0,
+            length: n_bodies as u32,
+        };
+        let depth_passes = (0..=shape.max_depth).map(|index| Pass { index, length: 0 });
+        let passes: Vec<Pass> = sort_passes
+            .chain([heads_pass])
+            .chain(depth_passes)
+            .collect();
+        let mut contents = vec![0; passes.len() * stride as usize];
+        for (index, pass) in passes.iter().enumerate() {
+            let mut data = UniformBuffer::new(Vec::new());
+            data.write(pass).unwrap();
+            let data = data.into_inner();
+            let offset = index * stride as usize;
+            contents[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        let passes_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("tree_passes_buffer"),
+            contents: &contents,
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let entry = |binding, ty| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let read_only = BufferBindingType::Storage { read_only: true };
+        let read_write = BufferBindingType::Storage { read_only: false };
+        let tree_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("tree_bind_group_layout"),
+            entries: &[
+                entry(0, read_only),
+                entry(1, read_only),
+                entry(2, BufferBindingType::Uniform),
+                entry(3, read_write),
+                entry(4, read_write),
+                entry(5, read_write),
+                entry(6, read_write),
+            ],
+        });
+        let pass_size = NonZeroU64::new(Pass::SHADER_SIZE.get());
+        let items_bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("items_bind_group_layout"),
+            entries: &[
+                entry(0, read_only),
+                entry(1, read_write),
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: pass_size,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let tree_buffers = [
+            pos_buffer,
+            mass_buffer,
+            &tree_params_buffer,
+            &octree_buffer,
+            &heads_buffer,
+            &counts_buffer,
+            &root_buffer,
+        ];
+        let entries: Vec<BindGroupEntry> = (tree_buffers.iter().enumerate())
+            .map(|(binding, buffer)| BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        let tree_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("tree_bind_group"),
+            layout: &tree_bind_group_layout,
+            entries: &entries,
+        });
+        let items_bind_group = |from: &Buffer, to: &Buffer| {
+            device.create_bind_group(&BindGroupDescriptor {
+                label: Some("items_bind_group"),
+                layout: &items_bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: from.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: to.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: BindingResource::Buffer(BufferBinding {
+                            buffer: &passes_buffer,
+                            offset: 0,
+                            size: pass_size,
+                        }),
+                    },
+                ],
+            })
+        };
+        let [items_a, items_b] = &items_buffers;
+        let items_bind_groups = [
+            items_bind_group(items_a, items_b),
+            items_bind_group(items_b, items_a),
+        ];
+
+        let shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("octree_build_shader"),
+            source: ShaderSource::Wgsl(Cow::Owned(format!(
+                "{}\n{}",
+                include_str!("octree_node.wgsl"),
+                include_str!("octree_build.wgsl")
+            ))),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("octree_build_pipeline_layout"),
+            bind_group_layouts: &[&tree_bind_group_layout, &items_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let entry_points = [
+            "bound_bodies",
+            "compute_keys",
+            "sort_count",
+            "scan",
+            "sort_scatter",
+            "mark_heads",
+            "emit_nodes",
+            "link_nodes",
+            "sum_nodes",
+        ];
+        let pipelines = entry_points
+            .into_iter()
+            .map(|entry_point| {
+                let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+                    label: Some(entry_point),
+                    layout: Some(&pipeline_layout),
+                    module: &shader,
+                    entry_point,
+                });
+                (entry_point, pipeline)
+            })
+            .collect();
+
+        Self {
+            pipelines,
+            tree_bind_group,
+            items_bind_groups,
+            octree_buffer,
+            stride,
+            n_bodies,
+            max_depth: shape.max_depth,
+        }
+    }
+
+    /// The tree, as rebuilt by every `record`.
+    pub(crate) fn octree_buffer(&self) -> &Buffer {
+        &self.octree_buffer
+    }
+
+    /// Record rebuilding the tree from the current positions, in a pass of its own.
+    pub(crate) fn record(&self, cmd_encoder: &mut CommandEncoder) {
+        let mut pass = cmd_encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: Some("octree_build_pass"),
+        });
+        pass.set_bind_group(TREE_GROUP, &self.tree_bind_group, &[]);
+        let n_workgroups = self.n_bodies.div_ceil(TREE_WG_SIZE) as u32;
+
+        // The root cell and the keys, then each digit's sort from one items buffer into the
+        // other, starting from whichever ends up back in the first
+        let first = SORT_PASSES as usize % 2;
+        self.dispatch(&mut pass, 1 - first, 0, "bound_bodies", 1);
+        self.dispatch(&mut pass, 1 - first, 0, "compute_keys", n_workgroups);
+        for index in 0..SORT_PASSES {
+            let from = (first + index as usize) % 2;
+            self.dispatch(&mut pass, from, index, "sort_count", n_workgroups);
+            self.dispatch(&mut pass, from, index, "scan", 1);
+            self.dispatch(&mut pass, from, index, "sort_scatter", n_workgroups);
+        }
+
+        let heads_pass = SORT_PASSES;
+        self.dispatch(&mut pass, 0, heads_pass, "mark_heads", n_workgroups);
+        self.dispatch(&mut pass, 0, heads_pass, "scan", 1);
+        self.dispatch(&mut pass, 0, heads_pass, "emit_nodes", n_workgroups);
+        self.dispatch(&mut pass, 0, heads_pass, "link_nodes", n_workgroups);
+        for depth in (0..=self.max_depth).rev() {
+            let depth_pass = heads_pass + 1 + depth;
+            self.dispatch(&mut pass, 0, depth_pass, "sum_nodes", n_workgroups);
+        }
+    }
+
+    fn dispatch<'a>(
+        &'a self,
+        pass: &mut ComputePass<'a>,
+        from: usize,
+        pass_index: u32,
+        entry_point: &str,
+        n_workgroups: u32,
+    ) {
+        let (_, pipeline) = self
+            .pipelines
+            .iter()
+            .find(|(name, _)| *name == entry_point)
+            .unwrap();
+        pass.set_pipeline(pipeline);
+        let offset = pass_index * self.stride;
+        pass.set_bind_group(ITEMS_GROUP, &self.items_bind_groups[from], &[offset]);
+        pass.dispatch_workgroups(n_workgroups, 1, 1);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::diagnostics::Moments;
+use crate::gpu::{KINEMATICS_GROUP, STATIC_GROUP};
+use encase::{ShaderSize, ShaderType, StorageBuffer};
+use glam::Vec3;
+use std::borrow::Cow;
+use wgpu::*;
+
+const REDUCE_WG_SIZE: usize = 128;
+const PARTIALS_GROUP: u32 = 2;
+
+/// Per-workgroup partial results of `reduce.wgsl`, laid out as its `Moments` struct.
+#[derive(ShaderType, Default)]
+struct GpuMoments {
+    mass: f32,
+    kinetic_energy: f32,
+    max_speed: f32,
+    max_acceleration: f32,
+    momentum: Vec3,
+    angular_momentum: Vec3,
+    mass_moment: Vec3,
+    bounds_min: Vec3,
+    bounds_max: Vec3,
+}
+
+/// Computes `Moments` of the bodies on the GPU, mapping back a single result through a small
+/// staging buffer instead of every body.
+pub(crate) struct Reduction {
+    bodies_pipeline: ComputePipeline,
+    partials_pipeline: ComputePipeline,
+    partials_bind_group: BindGroup,
+    partials_buffer: Buffer,
+    staging_buffer: Buffer,
+    n_workgroups: u32,
+}
+
+impl Reduction {
+    pub(crate) fn new(
+        device: &Device,
+        static_bind_group_layout: &BindGroupLayout,
+        kinematics_bind_group_layout: &BindGroupLayout,
+        n_bodies: usize,
+    ) -> Self {
+        let n_workgroups = n_bodies.div_ceil(REDUCE_WG_SIZE).max(1) as u32;
+        let partials_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("reduce_partials_buffer"),
+            size: n_workgroups as u64 * GpuMoments::SHADER_SIZE.get(),
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let staging_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("reduce_staging_buffer"),
+            size: GpuMoments::SHADER_SIZE.get(),
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let partials_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("reduce_partials_bind_group_layout"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+        let partials_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("reduce_partials_bind_group"),
+            layout: &partials_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: partials_buffer.as_entire_binding(),
+            }],
+        });
+
+        let reduce_shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("reduce_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("reduce.wgsl"))),
+        });
+        let reduce_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("reduce_pipeline_layout"),
+            bind_group_layouts: &[
+                static_bind_group_layout,
+                kinematics_bind_group_layout,
+                &partials_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let create_pipeline = |entry_point| {
+            device.create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&reduce_pipeline_layout),
+                module: &reduce_shader,
+                entry_point,
+            })
+        };
+
+        Self {
+            bodies_pipeline: create_pipeline("reduce_bodies"),
+            partials_pipeline: create_pipeline("reduce_partials"),
+            partials_bind_group,
+            partials_buffer,
+            staging_buffer,
+            n_workgroups,
+        }
+    }
+
+    /// Reduce the bodies bound by `kinematics_bind_group` and block until the result is back.
+    pub(crate) fn moments(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        static_bind_group: &BindGroup,
+        kinematics_bind_group: &BindGroup,
+    ) -> Moments {
+        let mut reduce_cmd_encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("reduce_cmd_encoder"),
+        });
+        {
+            let mut reduce_pass = reduce_cmd_encoder.begin_compute_pass(&ComputePassDescriptor {
+                label: Some("reduce_pass"),
+            });
+            // The reduction doesn't read the stage, so any of its offsets will do
+            reduce_pass.set_bind_group(STATIC_GROUP, static_bind_group, &[0]);
+            reduce_pass.set_bind_group(KINEMATICS_GROUP, kinematics_bind_group, &[]);
+            reduce_pass.set_bind_group(PARTIALS_GROUP, &self.partials_bind_group, &[]);
+            reduce_pass.set_pipeline(&self.bodies_pipeline);
+            reduce_pass.dispatch_workgroups(self.n_workgroups, 1, 1);
+            reduce_pass.set_pipeline(&self.partials_pipeline);
+            reduce_pass.dispatch_workgroups(1, 1, 1);
+        }
+        reduce_cmd_encoder.copy_buffer_to_buffer(
+            &self.partials_buffer,
+            0,
+            &self.staging_buffer,
+            0,
+            self.staging_buffer.size(),
+        );
+        queue.submit(Some(reduce_cmd_encoder.finish()));
+
+        let slice = self.staging_buffer.slice(..);
+        let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+        slice.map_async(MapMode::Read, move |v| sender.send(v).unwrap());
+        device.poll(Maintain::Wait);
+        pollster::block_on(receiver.receive());
+        let data = slice.get_mapped_range();
+        let mut moments = GpuMoments::default();
+        StorageBuffer::new(&*data).read(&mut moments).unwrap();
+        drop(data);
+        self.staging_buffer.unmap();
+
+        let total_mass = moments.mass as f64;
+        Moments {
+            total_mass,
+            kinetic_energy: moments.kinetic_energy as f64,
+            momentum: moments.momentum.as_dvec3(),
+            angular_momentum: moments.angular_momentum.as_dvec3(),
+            center_of_mass: if total_mass > 0.0 {
+                moments.mass_moment.as_dvec3() / total_mass
+            } else {
+                Default::default()
+            },
+            bounds_min: moments.bounds_min,
+            bounds_max: moments.bounds_max,
+            max_speed: moments.max_speed,
+            max_acceleration: moments.max_acceleration,
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::checkpoint::Checkpointer;
+use crate::diagnostics::DiagnosticsLog;
+use crate::{Config, Simulation, Snapshot};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::time::Instant;
+
+/// Advance `simulation` from `initial` by `n_steps` steps, without opening a window.
+///
+/// Per-step diagnostics are written to `steps.csv`, the final state of every body to
+/// `final_state.csv` and the position of the tracked bodies after every step to `tracks.csv`,
+/// all inside the output directory. The final state also goes to the dump snapshot, if given.
+pub fn run<S: Simulation>(
+    simulation: &mut S,
+    initial: &Snapshot,
+    n_steps: usize,
+    config: &Config,
+) -> io::Result<()> {
+    let bodies = &initial.bodies;
+    let output_dir = &config.output;
+    let tracked = &config.tracked;
+
+    if let Some(n) = tracked.iter().find(|n| **n >= simulation.n_bodies()) {
+        let message = format!(
+            "cannot track body {n}, there are only {}",
+            simulation.n_bodies()
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    fs::create_dir_all(output_dir)?;
+
+    let mut steps_csv = BufWriter::new(File::create(output_dir.join("steps.csv"))?);
+    writeln!(
+        steps_csv,
+        "step,time,time_step,step_seconds,max_speed,max_acceleration,kinetic_energy,momentum,\
+         angular_momentum"
+    )?;
+    let mut tracks_csv = BufWriter::new(File::create(output_dir.join("tracks.csv"))?);
+    writeln!(tracks_csv, "step,time,index,pos_x,pos_y,pos_z")?;
+
+    let mut checkpointer = Checkpointer::new(&config.checkpoints);
+    let (mut step, mut time) = (initial.step, initial.time);
+    let parameters = &config.parameters;
+    let mut diagnostics_log = DiagnosticsLog::for_config(config)?;
+    if let Some(log) = &mut diagnostics_log {
+        log.record(simulation, &bodies.masses, parameters, step, time)?;
+    }
+    for _ in 0..n_steps {
+        let dt = parameters.next_time_step(simulation);
+        let start = Instant::now();
+        simulation.step(dt);
+        step += 1;
+        time += dt as f64;
+        // Reducing the moments also waits for GPU backends to finish the step
+        let moments = simulation.moments();
+        let step_seconds = start.elapsed().as_secs_f64();
+        writeln!(
+            steps_csv,
+            "{step},{time},{dt},{step_seconds},{},{},{},{},{}",
+            moments.max_speed,
+            moments.max_acceleration,
+            moments.kinetic_energy,
+            moments.momentum.length(),
+            moments.angular_momentum.length()
+        )?;
+
+        if !tracked.is_empty() {
+            let positions = simulation.positions();
+            for &n in tracked {
+                let p = positions[n];
+                writeln!(tracks_csv, "{step},{time},{n},{},{},{}", p.x, p.y, p.z)?;
+            }
+        }
+
+        if let Some(log) = &mut diagnostics_log {
+            log.record(simulation, &bodies.masses, parameters, step, time)?;
+        }
+        checkpointer.save_if_due(simulation, bodies, step, time, parameters)?;
+    }
+    steps_csv.flush()?;
+    tracks_csv.flush()?;
+
+    let positions = simulation.positions();
+    let velocities = simulation.velocities();
+    let accelerations = simulation.accelerations();
+    let mut state_csv = BufWriter::new(File::create(output_dir.join("final_state.csv"))?);
+    writeln!(
+        state_csv,
+        "index,galaxy,mass,pos_x,pos_y,pos_z,vel_x,vel_y,vel_z,acc_x,acc_y,acc_z"
+    )?;
+    for n in 0..simulation.n_bodies() {
+        let (p, v, a) = (positions[n], velocities[n], accelerations[n]);
+        writeln!(
+            state_csv,
+            "{n},{},{},{},{},{},{},{},{},{},{},{}",
+            bodies.galaxies[n], bodies.masses[n], p.x, p.y, p.z, v.x, v.y, v.z, a.x, a.y, a.z
+        )?;
+    }
+    state_csv.flush()?;
+
+    if let Some(path) = &config.dump {
+        Snapshot::capture(simulation, bodies, step, time, parameters).save(path)?;
+    }
+    Ok(())
+}
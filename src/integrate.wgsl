@@ -0,0 +1,273 @@
+// Integrator kernels, appended to nbody.wgsl or nbodybh.wgsl, which declare `constrain` and
+// `acceleration`; the bodies and `params` are declared by bodies.wgsl. Each `Op` of
+// src/integrator.rs has an entry point that carries it out for one body, with the op's
+// coefficients in `stage` and `params.time_step` as the step.
+// The `block_` entry points make up the ticks of block steps instead; see `BlockSteps`.
+
+struct Stage {
+    coefficient: f32,
+    weight: f32,
+};
+
+// Positions, velocities, accelerations and jerks at the start of the step, and the running RK4
+// sums of the derivatives
+struct Saved {
+    position: vec3<f32>,
+    velocity: vec3<f32>,
+    acceleration: vec3<f32>,
+    jerk: vec3<f32>,
+    position_sum: vec3<f32>,
+    velocity_sum: vec3<f32>,
+};
+
+// Block step rung of body `i`, and the index of the `i`th body active this tick
+struct Slot {
+    rung: u32,
+    active_body: u32,
+};
+
+struct Blocks {
+    tick: u32,
+    n_active: atomic<u32>,
+    slots: array<Slot>,
+};
+
+// Workgroups to dispatch over the active bodies, laid out as indirect dispatch arguments; only
+// bound, in place of `saved`, for `block_compact` and `block_advance`
+struct Dispatch {
+    x: atomic<u32>,
+    y: u32,
+    z: u32,
+};
+
+@group(0) @binding(2) var<uniform> stage: Stage;
+@group(2) @binding(0) var<storage, read_write> saved: array<Saved>;
+@group(2) @binding(1) var<storage, read_write> blocks: Blocks;
+@group(2) @binding(2) var<storage, read_write> dispatch: Dispatch;
+
+// v += c dt a
+@compute
+@workgroup_size(64)
+fn kick(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    velocities[i_id] += stage.coefficient * params.time_step * accelerations[i_id];
+}
+
+// x += c dt v
+@compute
+@workgroup_size(64)
+fn drift(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    let pos = positions[i_id] + stage.coefficient * params.time_step * velocities[i_id];
+    positions[i_id] = constrain(pos);
+}
+
+@compute
+@workgroup_size(64)
+fn save(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    saved[i_id] = Saved(
+        positions[i_id],
+        velocities[i_id],
+        accelerations[i_id],
+        jerks[i_id],
+        vec3(0.0),
+        vec3(0.0),
+    );
+}
+
+// Accumulate the derivatives at this evaluation point, then move to the next one
+@compute
+@workgroup_size(64)
+fn rk4_stage(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    let vel = velocities[i_id];
+    let acc = accelerations[i_id];
+    var s = saved[i_id];
+    s.position_sum += stage.weight * vel;
+    s.velocity_sum += stage.weight * acc;
+    saved[i_id] = s;
+
+    let h = stage.coefficient * params.time_step;
+    positions[i_id] = constrain(s.position + h * vel);
+    velocities[i_id] = s.velocity + h * acc;
+}
+
+@compute
+@workgroup_size(64)
+fn rk4_finish(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    let s = saved[i_id];
+    let dt = params.time_step;
+    let pos = s.position + dt * (s.position_sum + stage.weight * velocities[i_id]);
+    velocities[i_id] = s.velocity + dt * (s.velocity_sum + stage.weight * accelerations[i_id]);
+    positions[i_id] = constrain(pos);
+}
+
+// Taylor-expand the saved state to the end of the step
+@compute
+@workgroup_size(64)
+fn hermite_predict(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    let s = saved[i_id];
+    let dt = params.time_step;
+    let pos = s.position + dt * (s.velocity + dt * (s.acceleration / 2.0 + dt * s.jerk / 6.0));
+    positions[i_id] = constrain(pos);
+    velocities[i_id] = s.velocity + dt * (s.acceleration + dt * s.jerk / 2.0);
+}
+
+// Correct the prediction with the accelerations and jerks evaluated there
+@compute
+@workgroup_size(64)
+fn hermite_correct(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    let s = saved[i_id];
+    let dt = params.time_step;
+    let acc = accelerations[i_id];
+    let vel = s.velocity + dt / 2.0 * (s.acceleration + acc)
+        + dt * dt / 12.0 * (s.jerk - jerks[i_id]);
+    let pos = s.position + dt / 2.0 * (s.velocity + vel) + dt * dt / 12.0 * (s.acceleration - acc);
+    velocities[i_id] = vel;
+    positions[i_id] = constrain(pos);
+}
+
+fn rung_time_step(rung: u32) -> f32 {
+    return params.time_step / f32(1u << rung);
+}
+
+fn ticks_per_step(rung: u32) -> u32 {
+    return 1u << (params.max_rung - rung);
+}
+
+// Rung that suits a body with acceleration `acc`
+fn ideal_rung(acc: vec3<f32>) -> u32 {
+    let ideal = params.eta * sqrt(params.softening / length(acc));
+    return u32(clamp(ceil(log2(params.time_step / ideal)), 0.0, f32(params.max_rung)));
+}
+
+// Shallowest rung a body can step on from `tick` onwards, limited by the trailing zeros of `tick`
+fn shallowest_rung(tick: u32) -> u32 {
+    var rung = params.max_rung;
+    var t = tick;
+    loop {
+        if rung == 0u || (t & 1u) != 0u {
+            break;
+        }
+        t = t >> 1u;
+        rung -= 1u;
+    }
+    return rung;
+}
+
+@compute
+@workgroup_size(64)
+fn assign_rungs(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    blocks.slots[i_id].rung = ideal_rung(accelerations[i_id]);
+}
+
+// Open the steps starting at this tick, then drift every body up to the next
+@compute
+@workgroup_size(64)
+fn block_open(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    let rung = blocks.slots[i_id].rung;
+    if blocks.tick % ticks_per_step(rung) == 0u {
+        velocities[i_id] += 0.5 * rung_time_step(rung) * accelerations[i_id];
+    }
+    let tick_time_step = rung_time_step(params.max_rung);
+    positions[i_id] = constrain(positions[i_id] + tick_time_step * velocities[i_id]);
+}
+
+// Gather the bodies whose steps end at the next tick at the front of the slots, counting the
+// workgroups that cover them, so the active-body kernels are only dispatched over those
+@compute
+@workgroup_size(64)
+fn block_compact(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    if (blocks.tick + 1u) % ticks_per_step(blocks.slots[i_id].rung) == 0u {
+        let slot = atomicAdd(&blocks.n_active, 1u);
+        blocks.slots[slot].active_body = i_id;
+        if slot % 64u == 0u {
+            atomicAdd(&dispatch.x, 1u);
+        }
+    }
+}
+
+// Inactive bodies keep the softening lengths of their last force evaluation, as they do their
+// accelerations
+@compute
+@workgroup_size(64)
+fn compute_active_softening_lengths(
+    @builtin(global_invocation_id) global_invocation_id: vec3<u32>
+) {
+    let slot = global_invocation_id.x;
+    if slot >= atomicLoad(&blocks.n_active) {
+        return;
+    }
+    let i_id = blocks.slots[slot].active_body;
+    softening_lengths[i_id] = adaptive_length(i_id);
+}
+
+@compute
+@workgroup_size(64)
+fn compute_active_accelerations(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let slot = global_invocation_id.x;
+    if slot >= atomicLoad(&blocks.n_active) {
+        return;
+    }
+    let i_id = blocks.slots[slot].active_body;
+    accelerations[i_id] = acceleration(i_id);
+}
+
+// Close the steps of the active bodies and pick their next rungs
+@compute
+@workgroup_size(64)
+fn block_close(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let slot = global_invocation_id.x;
+    if slot >= atomicLoad(&blocks.n_active) {
+        return;
+    }
+    let i_id = blocks.slots[slot].active_body;
+    let acc = accelerations[i_id];
+    velocities[i_id] += 0.5 * rung_time_step(blocks.slots[i_id].rung) * acc;
+    blocks.slots[i_id].rung = max(ideal_rung(acc), shallowest_rung(blocks.tick + 1u));
+}
+
+@compute
+@workgroup_size(1)
+fn block_advance() {
+    blocks.tick += 1u;
+    atomicStore(&blocks.n_active, 0u);
+    atomicStore(&dispatch.x, 0u);
+}
@@ -0,0 +1,323 @@
+use crate::{Bodies, Parameters};
+use clap::ValueEnum;
+use glam::Vec3;
+use serde::Deserialize;
+
+/// Time integration scheme, shared by every backend.
+///
+/// Each integrator is a fixed sequence of [`Op`]s per step, which the CPU backends run on their
+/// state vectors and the GPU backends dispatch as the matching kernels of `integrate.wgsl`.
+/// After every step the accelerations (and jerks, for Hermite) belong to the new state, so they
+/// carry over to the next step without another force evaluation.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    /// Explicit Euler; first order, for comparison only
+    Euler,
+    /// Kick-drift-kick leapfrog; second order and symplectic
+    #[default]
+    Leapfrog,
+    /// Classic fourth-order Runge-Kutta; not symplectic
+    Rk4,
+    /// Yoshida's fourth-order composition of three leapfrog steps; symplectic
+    Yoshida4,
+    /// Fourth-order Hermite predictor-corrector, which needs jerks as well as accelerations
+    Hermite4,
+}
+
+/// One stage of an integrator step. `x`, `v`, `a` and `j` are the positions, velocities,
+/// accelerations and jerks, and `dt` the step.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    /// `v += c dt a`
+    Kick(f32),
+    /// `x += c dt v`
+    Drift(f32),
+    /// Evaluate `a` (and `j` for Hermite) at the current `x` and `v`
+    Force,
+    /// Remember `x`, `v`, `a` and `j` as `x0`, `v0`, `a0` and `j0`, and clear the RK4 sums
+    Save,
+    /// Add `weight` times the current derivatives `(v, a)` to the sums, then move to the next
+    /// evaluation point `(x0, v0) + next dt (v, a)`
+    Rk4Stage { weight: f32, next: f32 },
+    /// `(x, v) = (x0, v0) + dt (sums + weight (v, a))`
+    Rk4Finish { weight: f32 },
+    /// Taylor-expand `x0` and `v0` to the end of the step using `a0` and `j0`
+    HermitePredict,
+    /// Correct the predicted `x` and `v` with the `a` and `j` evaluated there
+    HermiteCorrect,
+}
+
+/// Yoshida (1990) coefficients: w1 = 1 / (2 - 2^(1/3)) and w0 = 1 - 2 w1.
+const YOSHIDA_W1: f32 = 1.351_207_2;
+const YOSHIDA_W0: f32 = -1.702_414_4;
+
+impl Integrator {
+    pub fn ops(self) -> &'static [Op] {
+        use Op::*;
+        match self {
+            Integrator::Euler => &[Drift(1.0), Kick(1.0), Force],
+            Integrator::Leapfrog => &[Kick(0.5), Drift(1.0), Force, Kick(0.5)],
+            Integrator::Rk4 => &[
+                Save,
+                Rk4Stage {
+                    weight: 1.0 / 6.0,
+                    next: 0.5,
+                },
+                Force,
+                Rk4Stage {
+                    weight: 1.0 / 3.0,
+                    next: 0.5,
+                },
+                Force,
+                Rk4Stage {
+                    weight: 1.0 / 3.0,
+                    next: 1.0,
+                },
+                Force,
+                Rk4Finish { weight: 1.0 / 6.0 },
+                Force,
+            ],
+            Integrator::Yoshida4 => &[
+                Kick(0.5 * YOSHIDA_W1),
+                Drift(YOSHIDA_W1),
+                Force,
+                Kick(0.5 * (YOSHIDA_W1 + YOSHIDA_W0)),
+                Drift(YOSHIDA_W0),
+                Force,
+                Kick(0.5 * (YOSHIDA_W0 + YOSHIDA_W1)),
+                Drift(YOSHIDA_W1),
+                Force,
+                Kick(0.5 * YOSHIDA_W1),
+            ],
+            Integrator::Hermite4 => &[Save, HermitePredict, Force, HermiteCorrect],
+        }
+    }
+
+    /// Whether force evaluations must also produce jerks.
+    pub fn needs_jerks(self) -> bool {
+        self == Integrator::Hermite4
+    }
+}
+
+/// Deepest block step rung supported, which splits a step into 65536 ticks.
+pub const MAX_RUNG: u32 = 16;
+
+/// Power-of-two block time steps: each body sits on a rung `r` and takes steps of `dt / 2^r`,
+/// where `dt` is the length of a whole step of the simulation.
+///
+/// A step is split into `2^max_rung` ticks. Every tick all bodies drift, but only those whose own
+/// step ends there (the active ones) have their accelerations evaluated and are kicked, so bodies
+/// in quiet regions cost a fraction of those in close encounters. Rungs follow the same
+/// `eta * sqrt(softening / |a|)` criterion as adaptive steps, and a body may only move to a
+/// shallower rung at a tick that is also a step boundary of that rung.
+#[derive(Clone, Copy, Debug)]
+pub struct BlockSteps {
+    pub max_rung: u32,
+    pub eta: f32,
+    pub softening: f32,
+}
+
+impl BlockSteps {
+    /// Block steps as configured by `parameters`, or `None` if every body takes whole steps.
+    pub fn new(parameters: &Parameters) -> Option<Self> {
+        (parameters.max_rung > 0).then_some(Self {
+            max_rung: parameters.max_rung,
+            eta: parameters.eta,
+            softening: parameters.softening,
+        })
+    }
+
+    /// Ticks in a whole step.
+    pub fn ticks(&self) -> u32 {
+        1 << self.max_rung
+    }
+
+    /// Ticks in a single step of a body on `rung`.
+    pub fn ticks_per_step(&self, rung: u32) -> u32 {
+        1 << (self.max_rung - rung)
+    }
+
+    /// Rung that suits a body with `acceleration`, when a whole step is `dt` long.
+    pub fn rung(&self, dt: f32, acceleration: Vec3) -> u32 {
+        let ideal = self.eta * (self.softening / acceleration.length()).sqrt();
+        // A NaN rung (from a zero softening and acceleration) saturates to rung 0
+        (dt / ideal).log2().ceil().clamp(0.0, self.max_rung as f32) as u32
+    }
+
+    /// Shallowest rung that a body can step on from `tick` onwards.
+    pub fn shallowest_rung(&self, tick: u32) -> u32 {
+        self.max_rung - tick.trailing_zeros().min(self.max_rung)
+    }
+}
+
+/// Fills in accelerations, and jerks if asked for, from positions and velocities.
+pub trait Force {
+    fn evaluate(
+        &mut self,
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        accelerations: &mut [Vec3],
+        jerks: Option<&mut [Vec3]>,
+    );
+
+    /// Fill in the accelerations of just the `active` bodies, leaving the rest alone.
+    fn evaluate_active(
+        &mut self,
+        positions: &[Vec3],
+        velocities: &[Vec3],
+        active: &[usize],
+        accelerations: &mut [Vec3],
+    );
+}
+
+/// State vectors of a CPU backend, plus the scratch space the integrators need.
+#[derive(Clone, Debug, Default)]
+pub struct State {
+    pub positions: Vec<Vec3>,
+    pub velocities: Vec<Vec3>,
+    pub accelerations: Vec<Vec3>,
+    pub jerks: Vec<Vec3>,
+    /// Block step rung of every body, if block steps are in use
+    pub rungs: Vec<u32>,
+
+    saved_positions: Vec<Vec3>,
+    saved_velocities: Vec<Vec3>,
+    saved_accelerations: Vec<Vec3>,
+    saved_jerks: Vec<Vec3>,
+    position_sums: Vec<Vec3>,
+    velocity_sums: Vec<Vec3>,
+}
+
+impl State {
+    /// State for `bodies`, with accelerations (and jerks) evaluated by `force`.
+    pub fn new(bodies: &Bodies, integrator: Integrator, force: &mut impl Force) -> Self {
+        let n_bodies = bodies.len();
+        let mut state = Self {
+            positions: bodies.positions.clone(),
+            velocities: bodies.velocities.clone(),
+            accelerations: vec![Vec3::ZERO; n_bodies],
+            jerks: vec![Vec3::ZERO; n_bodies],
+            rungs: vec![0; n_bodies],
+            ..Default::default()
+        };
+        state.evaluate(integrator, force);
+        state
+    }
+
+    /// Put every body on the rung its current acceleration calls for.
+    pub fn assign_rungs(&mut self, blocks: &BlockSteps, dt: f32) {
+        for (rung, a) in self.rungs.iter_mut().zip(&self.accelerations) {
+            *rung = blocks.rung(dt, *a);
+        }
+    }
+
+    /// Advance by one whole step of `dt` using block steps, which are always kick-drift-kick
+    /// leapfrog.
+    pub fn block_step(&mut self, blocks: &BlockSteps, dt: f32, force: &mut impl Force) {
+        let tick_dt = dt / blocks.ticks() as f32;
+        let rung_dt = |rung: u32| dt / (1 << rung) as f32;
+        for tick in 0..blocks.ticks() {
+            // Open the steps starting at this tick, then move everyone up to the next
+            for n in 0..self.positions.len() {
+                let rung = self.rungs[n];
+                if tick % blocks.ticks_per_step(rung) == 0 {
+                    self.velocities[n] += 0.5 * rung_dt(rung) * self.accelerations[n];
+                }
+                self.positions[n] += tick_dt * self.velocities[n];
+            }
+
+            // Close the steps ending at the next tick
+            let active: Vec<usize> = (0..self.positions.len())
+                .filter(|n| (tick + 1) % blocks.ticks_per_step(self.rungs[*n]) == 0)
+                .collect();
+            force.evaluate_active(
+                &self.positions,
+                &self.velocities,
+                &active,
+                &mut self.accelerations,
+            );
+            let shallowest_rung = blocks.shallowest_rung(tick + 1);
+            for n in active {
+                let a = self.accelerations[n];
+                self.velocities[n] += 0.5 * rung_dt(self.rungs[n]) * a;
+                self.rungs[n] = blocks.rung(dt, a).max(shallowest_rung);
+            }
+        }
+    }
+
+    /// Advance by one step of `dt`.
+    pub fn step(&mut self, integrator: Integrator, dt: f32, force: &mut impl Force) {
+        for op in integrator.ops() {
+            self.apply(*op, integrator, dt, force);
+        }
+    }
+
+    fn evaluate(&mut self, integrator: Integrator, force: &mut impl Force) {
+        let jerks = integrator.needs_jerks().then_some(&mut self.jerks[..]);
+        force.evaluate(
+            &self.positions,
+            &self.velocities,
+            &mut self.accelerations,
+            jerks,
+        );
+    }
+
+    fn apply(&mut self, op: Op, integrator: Integrator, dt: f32, force: &mut impl Force) {
+        if op == Op::Force {
+            return self.evaluate(integrator, force);
+        }
+        let Self {
+            positions: x,
+            velocities: v,
+            accelerations: a,
+            jerks: j,
+            rungs: _,
+            saved_positions: x0,
+            saved_velocities: v0,
+            saved_accelerations: a0,
+            saved_jerks: j0,
+            position_sums,
+            velocity_sums,
+        } = self;
+        match op {
+            Op::Kick(c) => v.iter_mut().zip(a).for_each(|(v, a)| *v += c * dt * *a),
+            Op::Drift(c) => x.iter_mut().zip(v).for_each(|(x, v)| *x += c * dt * *v),
+            Op::Force => unreachable!(),
+            Op::Save => {
+                x0.clone_from(x);
+                v0.clone_from(v);
+                a0.clone_from(a);
+                j0.clone_from(j);
+                *position_sums = vec![Vec3::ZERO; x.len()];
+                *velocity_sums = vec![Vec3::ZERO; x.len()];
+            }
+            Op::Rk4Stage { weight, next } => {
+                for n in 0..x.len() {
+                    position_sums[n] += weight * v[n];
+                    velocity_sums[n] += weight * a[n];
+                    x[n] = x0[n] + next * dt * v[n];
+                    v[n] = v0[n] + next * dt * a[n];
+                }
+            }
+            Op::Rk4Finish { weight } => {
+                for n in 0..x.len() {
+                    x[n] = x0[n] + dt * (position_sums[n] + weight * v[n]);
+                    v[n] = v0[n] + dt * (velocity_sums[n] + weight * a[n]);
+                }
+            }
+            Op::HermitePredict => {
+                for n in 0..x.len() {
+                    x[n] = x0[n] + dt * (v0[n] + dt * (a0[n] / 2.0 + dt * j0[n] / 6.0));
+                    v[n] = v0[n] + dt * (a0[n] + dt * j0[n] / 2.0);
+                }
+            }
+            Op::HermiteCorrect => {
+                for n in 0..x.len() {
+                    v[n] = v0[n] + dt / 2.0 * (a0[n] + a[n]) + dt * dt / 12.0 * (j0[n] - j[n]);
+                    x[n] = x0[n] + dt / 2.0 * (v0[n] + v[n]) + dt * dt / 12.0 * (a0[n] - a[n]);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// encase's ShaderType derive emits a never-called `check` fn per field, which newer rustc flags
+#![allow(dead_code)]
+
+pub mod bodies;
+pub mod checkpoint;
+pub mod config;
+pub mod cpu;
+pub mod cpu_bh;
+pub mod diagnostics;
+pub mod force;
+pub mod frontend;
+pub mod gpu;
+pub mod gpu_bh;
+mod gpu_octree;
+mod gpu_reduce;
+pub mod headless;
+pub mod integrator;
+pub mod models;
+pub mod octree;
+pub mod octree_maxdepth;
+pub mod octree_morton;
+pub mod render;
+pub mod scenario;
+pub mod simulation;
+pub mod snapshot;
+pub mod softening;
+pub mod solar_system;
+
+pub use crate::bodies::Bodies;
+pub use crate::config::{Config, Parameters};
+pub use crate::cpu::CpuSimulation;
+pub use crate::cpu_bh::CpuBhSimulation;
+pub use crate::frontend::{run, Backend};
+pub use crate::gpu::GpuSimulation;
+pub use crate::gpu_bh::GpuBhSimulation;
+pub use crate::simulation::Simulation;
+pub use crate::snapshot::Snapshot;
@@ -0,0 +1,418 @@
+use crate::Bodies;
+use glam::{DVec3, Quat, Vec3};
+use rand::Rng;
+use serde::Deserialize;
+use std::f64::consts::PI;
+
+/// Every this many bodies of a model is a light source when rendering.
+const EMITTER_STRIDE: usize = 20;
+
+/// Fraction of the total mass kept when sampling radii of untruncated profiles, so the few
+/// outermost bodies don't end up arbitrarily far away.
+const MASS_CUTOFF: f64 = 0.999;
+
+/// Plummer sphere of `total_mass` and scale radius `scale_radius`, in equilibrium under
+/// gravitational constant `g`, following Aarseth, Hénon & Wielen (1974).
+pub fn plummer<R: Rng>(
+    rng: &mut R,
+    n_bodies: usize,
+    total_mass: f32,
+    scale_radius: f32,
+    g: f32,
+) -> Bodies {
+    let (m, a, g) = (total_mass as f64, scale_radius as f64, g as f64);
+    sample_model(rng, n_bodies, total_mass, |rng| {
+        let mass_fraction = rng.gen_range(0.0..MASS_CUTOFF);
+        let r = a / (mass_fraction.powf(-2.0 / 3.0) - 1.0).sqrt();
+
+        // The speed in units of the local escape speed has distribution q^2 (1 - q^2)^(7/2)
+        let q = loop {
+            let q: f64 = rng.gen();
+            let y = rng.gen_range(0.0..0.1);
+            if y < q * q * (1.0 - q * q).powf(3.5) {
+                break q;
+            }
+        };
+        let v_escape = (2.0 * g * m).sqrt() * (r * r + a * a).powf(-0.25);
+        (r, q * v_escape)
+    })
+}
+
+/// Hernquist (1990) profile of `total_mass` and scale radius `scale_radius`, with speeds drawn
+/// from its isotropic distribution function.
+pub fn hernquist<R: Rng>(
+    rng: &mut R,
+    n_bodies: usize,
+    total_mass: f32,
+    scale_radius: f32,
+    g: f32,
+) -> Bodies {
+    let (m, a, g) = (total_mass as f64, scale_radius as f64, g as f64);
+    let v_g = (g * m / a).sqrt();
+
+    // f(E) up to a constant, in terms of q = sqrt(E a / G M) for binding energy E
+    let distribution_function = |binding_energy: f64| {
+        let q = (binding_energy / (v_g * v_g))
+            .clamp(0.0, 1.0 - 1e-12)
+            .sqrt();
+        let q2 = q * q;
+        (3.0 * q.asin()
+            + q * (1.0 - q2).sqrt() * (1.0 - 2.0 * q2) * (8.0 * q2 * q2 - 8.0 * q2 - 3.0))
+            / (1.0 - q2).powf(2.5)
+    };
+
+    sample_model(rng, n_bodies, total_mass, |rng| {
+        let root_mass_fraction = rng.gen_range(0.0..MASS_CUTOFF).sqrt();
+        let r = a * root_mass_fraction / (1.0 - root_mass_fraction);
+
+        let potential = g * m / (r + a);
+        let v = sample_speed(rng, (2.0 * potential).sqrt(), |v| {
+            v * v * distribution_function(potential - 0.5 * v * v)
+        });
+        (r, v)
+    })
+}
+
+/// King (1966) model of `total_mass` with core radius `scale_radius` and dimensionless central
+/// potential `w0`, which sets the concentration (tidal radius over core radius).
+pub fn king<R: Rng>(
+    rng: &mut R,
+    n_bodies: usize,
+    total_mass: f32,
+    scale_radius: f32,
+    w0: f32,
+    g: f32,
+) -> Bodies {
+    // Solve for W(r) in units where G = sigma = r0 = 1
+    let profile = KingProfile::new(w0 as f64);
+    let length_scale = scale_radius as f64;
+    let mass_scale = total_mass as f64 / profile.total_mass();
+    let velocity_scale = (g as f64 * mass_scale / length_scale).sqrt();
+
+    sample_model(rng, n_bodies, total_mass, |rng| {
+        let (r, w) = profile.sample_radius(rng.gen());
+        let v = sample_speed(rng, (2.0 * w).sqrt(), |v| {
+            v * v * ((w - 0.5 * v * v).exp() - 1.0)
+        });
+        (r * length_scale, v * velocity_scale)
+    })
+}
+
+/// A rotationally supported disk galaxy, with an optional bulge and dark halo.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DiskGalaxy {
+    pub n_disk: usize,
+    pub disk_mass: f32,
+    /// Scale length of the exponential surface density
+    pub disk_scale_length: f32,
+    /// Scale height of the sech² vertical profile; a tenth of the scale length if not given
+    pub disk_scale_height: Option<f32>,
+    /// Bodies of the Hernquist bulge
+    #[serde(default)]
+    pub n_bulge: usize,
+    #[serde(default)]
+    pub bulge_mass: f32,
+    #[serde(default = "default_scale_radius")]
+    pub bulge_scale_radius: f32,
+    /// Bodies of the Plummer halo
+    #[serde(default)]
+    pub n_halo: usize,
+    #[serde(default)]
+    pub halo_mass: f32,
+    #[serde(default = "default_scale_radius")]
+    pub halo_scale_radius: f32,
+    /// Direction of the disk's angular momentum
+    #[serde(default = "default_spin_axis")]
+    pub spin_axis: Vec3,
+}
+
+impl DiskGalaxy {
+    pub fn total_mass(&self) -> f32 {
+        self.disk_mass + self.bulge_mass + self.halo_mass
+    }
+}
+
+/// Disk galaxy at rest at the origin. Disk bodies move on circular orbits, with speeds from the
+/// mass enclosed by their (spherical) radius and the force law's `softening`; bulge and halo
+/// bodies get isotropic speeds from their own equilibrium profiles.
+pub fn disk_galaxy<R: Rng>(rng: &mut R, galaxy: &DiskGalaxy, g: f32, softening: f32) -> Bodies {
+    let scale_length = galaxy.disk_scale_length as f64;
+    let scale_height = galaxy
+        .disk_scale_height
+        .unwrap_or(galaxy.disk_scale_length / 10.0) as f64;
+
+    // The radius has density x e^-x in units of the scale length, i.e. Gamma(2): the sum of
+    // two exponential variates. Heights follow a sech² profile.
+    let disk_positions: Vec<DVec3> = (0..galaxy.n_disk)
+        .map(|_| {
+            let u: f64 = 1.0 - rng.gen::<f64>();
+            let v: f64 = 1.0 - rng.gen::<f64>();
+            let radius = -scale_length * (u * v).ln();
+            let phi = rng.gen_range(0.0..2.0 * PI);
+            let z = scale_height
+                * rng
+                    .gen_range(-1.0f64..1.0)
+                    .clamp(-0.999_999, 0.999_999)
+                    .atanh();
+            DVec3::new(radius * phi.cos(), radius * phi.sin(), z)
+        })
+        .collect();
+
+    let mut spheroids = Bodies::default();
+    if galaxy.n_bulge > 0 {
+        let bulge = hernquist(
+            rng,
+            galaxy.n_bulge,
+            galaxy.bulge_mass,
+            galaxy.bulge_scale_radius,
+            g,
+        );
+        spheroids.append(bulge);
+    }
+    if galaxy.n_halo > 0 {
+        spheroids.append(plummer(
+            rng,
+            galaxy.n_halo,
+            galaxy.halo_mass,
+            galaxy.halo_scale_radius,
+            g,
+        ));
+    }
+
+    // Mass enclosed by each radius, from every component's bodies
+    let disk_body_mass = galaxy.disk_mass as f64 / galaxy.n_disk.max(1) as f64;
+    let mut shells: Vec<(f64, f64)> = disk_positions
+        .iter()
+        .map(|p| (p.length(), disk_body_mass))
+        .chain(
+            spheroids
+                .positions
+                .iter()
+                .zip(&spheroids.masses)
+                .map(|(p, m)| (p.as_dvec3().length(), *m as f64)),
+        )
+        .collect();
+    shells.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut enclosed_mass = 0.0;
+    let enclosed: Vec<(f64, f64)> = shells
+        .iter()
+        .map(|(r, m)| {
+            enclosed_mass += m;
+            (*r, enclosed_mass)
+        })
+        .collect();
+    let mass_within = |r: f64| {
+        let i = enclosed.partition_point(|(shell, _)| *shell <= r);
+        if i == 0 {
+            0.0
+        } else {
+            enclosed[i - 1].1
+        }
+    };
+
+    let (g, softening_sqrd) = (g as f64, (softening as f64).powi(2));
+    let mut bodies = Bodies::default();
+    for (n, position) in disk_positions.iter().enumerate() {
+        let r = position.length();
+        let speed = (g * mass_within(r) * r * r / (r * r + softening_sqrd).powf(1.5)).sqrt();
+        let tangent = DVec3::Z.cross(*position).normalize_or_zero();
+        bodies.push(
+            disk_body_mass as f32,
+            1.0,
+            position.as_vec3(),
+            (tangent * speed).as_vec3(),
+            n % EMITTER_STRIDE == 0,
+        );
+    }
+    bodies.append(spheroids);
+
+    bodies.to_center_of_mass_frame();
+    bodies.rotate(Quat::from_rotation_arc(
+        Vec3::Z,
+        galaxy.spin_axis.normalize(),
+    ));
+    bodies
+}
+
+/// Two disk galaxies approaching each other on a parabolic orbit in the xy plane, `separation`
+/// apart now and `pericenter` apart at closest approach, with their joint centre of mass at rest
+/// at the origin. Bodies of `primary` are tagged galaxy 0 and those of `secondary` galaxy 1.
+pub fn parabolic_encounter<R: Rng>(
+    rng: &mut R,
+    primary: &DiskGalaxy,
+    secondary: &DiskGalaxy,
+    pericenter: f32,
+    separation: f32,
+    g: f32,
+    softening: f32,
+) -> Bodies {
+    let (m_1, m_2) = (primary.total_mass() as f64, secondary.total_mass() as f64);
+    let total_mass = m_1 + m_2;
+    let (q, d) = (
+        pericenter as f64,
+        (separation as f64).max(pericenter as f64),
+    );
+
+    // Relative orbit r = p / (1 + cos f) with semi-latus rectum p = 2q, on the incoming branch
+    let semi_latus_rectum = 2.0 * q;
+    let true_anomaly = -(semi_latus_rectum / d - 1.0).clamp(-1.0, 1.0).acos();
+    let (sin_f, cos_f) = true_anomaly.sin_cos();
+    let h = (g as f64 * total_mass / semi_latus_rectum).sqrt();
+    let relative_position = DVec3::new(d * cos_f, d * sin_f, 0.0);
+    let relative_velocity = DVec3::new(-h * sin_f, h * (1.0 + cos_f), 0.0);
+
+    let mut bodies = disk_galaxy(rng, primary, g, softening);
+    bodies.translate(
+        (relative_position * (-m_2 / total_mass)).as_vec3(),
+        (relative_velocity * (-m_2 / total_mass)).as_vec3(),
+    );
+    let mut companion = disk_galaxy(rng, secondary, g, softening);
+    companion.translate(
+        (relative_position * (m_1 / total_mass)).as_vec3(),
+        (relative_velocity * (m_1 / total_mass)).as_vec3(),
+    );
+    companion.set_galaxy(1);
+    bodies.append(companion);
+    bodies
+}
+
+/// Place `n_bodies` of equal mass at isotropic random directions, with radius and speed from
+/// `sample`, then move them into their centre-of-mass frame.
+fn sample_model<R: Rng>(
+    rng: &mut R,
+    n_bodies: usize,
+    total_mass: f32,
+    mut sample: impl FnMut(&mut R) -> (f64, f64),
+) -> Bodies {
+    let mut bodies = Bodies::default();
+    let mass = total_mass / n_bodies as f32;
+    for n in 0..n_bodies {
+        let (r, v) = sample(rng);
+        let position = random_direction(rng) * r;
+        let velocity = random_direction(rng) * v;
+        bodies.push(
+            mass,
+            1.0,
+            position.as_vec3(),
+            velocity.as_vec3(),
+            n % EMITTER_STRIDE == 0,
+        );
+    }
+    bodies.to_center_of_mass_frame();
+    bodies
+}
+
+/// Rejection-sample a speed in `[0, v_max]` with unnormalized density `weight`.
+fn sample_speed<R: Rng>(rng: &mut R, v_max: f64, weight: impl Fn(f64) -> f64) -> f64 {
+    if v_max <= 0.0 {
+        return 0.0;
+    }
+    // Bound the density from a scan, with some headroom since the peak may fall between points
+    let weight_max = (0..=64)
+        .map(|i| weight(v_max * i as f64 / 64.0))
+        .fold(0.0, f64::max)
+        * 1.2;
+    loop {
+        let v = rng.gen_range(0.0..v_max);
+        if rng.gen_range(0.0..weight_max) < weight(v) {
+            return v;
+        }
+    }
+}
+
+fn random_direction<R: Rng>(rng: &mut R) -> DVec3 {
+    let z: f64 = rng.gen_range(-1.0..=1.0);
+    let phi = rng.gen_range(0.0..2.0 * PI);
+    let s = (1.0 - z * z).sqrt();
+    DVec3::new(s * phi.cos(), s * phi.sin(), z)
+}
+
+/// Numerical solution of Poisson's equation for a King model, tabulated out to the tidal radius.
+struct KingProfile {
+    radii: Vec<f64>,
+    potentials: Vec<f64>,
+    enclosed_masses: Vec<f64>,
+}
+
+impl KingProfile {
+    const DR: f64 = 1e-3;
+
+    fn new(w0: f64) -> Self {
+        // Density relative to the centre, for the distribution function f(E) ~ exp(E) - 1
+        let density = |w: f64| {
+            if w <= 0.0 {
+                return 0.0;
+            }
+            w.exp() * erf(w.sqrt()) - (4.0 * w / PI).sqrt() * (1.0 + 2.0 * w / 3.0)
+        };
+        let rho0 = density(w0);
+        // W'' = -(2/r) W' - 9 rho(W) / rho0, integrated with RK4 in (W, W', M)
+        let derivatives = |r: f64, [w, dw, _]: [f64; 3]| {
+            let rho = density(w) / rho0;
+            [dw, -2.0 * dw / r - 9.0 * rho, 9.0 * r * r * rho]
+        };
+
+        // Start just off-centre using the series expansion W ~ W0 - 3/2 r^2
+        let mut r = Self::DR;
+        let mut state = [w0 - 1.5 * r * r, -3.0 * r, 3.0 * r * r * r];
+        let mut profile = Self {
+            radii: vec![0.0, r],
+            potentials: vec![w0, state[0]],
+            enclosed_masses: vec![0.0, state[2]],
+        };
+        while state[0] > 0.0 {
+            let h = Self::DR;
+            let add = |a: [f64; 3], b: [f64; 3], s: f64| [0, 1, 2].map(|i| a[i] + s * b[i]);
+            let k1 = derivatives(r, state);
+            let k2 = derivatives(r + h / 2.0, add(state, k1, h / 2.0));
+            let k3 = derivatives(r + h / 2.0, add(state, k2, h / 2.0));
+            let k4 = derivatives(r + h, add(state, k3, h));
+            state = [0, 1, 2].map(|i| state[i] + h / 6.0 * (k1[i] + 2.0 * (k2[i] + k3[i]) + k4[i]));
+            r += h;
+
+            profile.radii.push(r);
+            profile.potentials.push(state[0].max(0.0));
+            profile.enclosed_masses.push(state[2]);
+        }
+        profile
+    }
+
+    fn total_mass(&self) -> f64 {
+        *self.enclosed_masses.last().unwrap()
+    }
+
+    /// Radius enclosing `mass_fraction` of the mass, and the potential W there.
+    fn sample_radius(&self, mass_fraction: f64) -> (f64, f64) {
+        let target = mass_fraction * self.total_mass();
+        let i = self
+            .enclosed_masses
+            .partition_point(|m| *m < target)
+            .clamp(1, self.radii.len() - 1);
+        let (m_0, m_1) = (self.enclosed_masses[i - 1], self.enclosed_masses[i]);
+        let t = if m_1 > m_0 {
+            (target - m_0) / (m_1 - m_0)
+        } else {
+            0.0
+        };
+        let lerp = |values: &[f64]| values[i - 1] + t * (values[i] - values[i - 1]);
+        (lerp(&self.radii), lerp(&self.potentials))
+    }
+}
+
+/// Error function, Abramowitz & Stegun 7.1.26 (max error 1.5e-7).
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    (1.0 - poly * (-x * x).exp()).copysign(x)
+}
+
+fn default_scale_radius() -> f32 {
+    1.0
+}
+
+fn default_spin_axis() -> Vec3 {
+    Vec3::Z
+}
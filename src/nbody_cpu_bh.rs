@@ -0,0 +1,5 @@
+use nbody::CpuBhSimulation;
+
+fn main() {
+    nbody::run::<CpuBhSimulation>();
+}
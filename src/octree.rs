@@ -1,4 +1,3 @@
-use crate::WORLD_SIZE;
 use encase::ShaderType;
 use glam::Vec3;
 
@@ -14,101 +13,40 @@ pub struct OctreeNode {
     max_depth: u32,
 }
 
+//cells this deep are finer than f32 positions can tell apart, so bodies there would never
+//separate; instead they take the first free child slot, chaining on through the last slot
+const MAX_SPLIT_DEPTH: u32 = 32;
+
 impl OctreeNode {
-    pub fn new_tree(positions: &[Vec3], masses: &[f32]) -> Vec<Self> {
-        let root_node = Self::new_dummy();
-        let root_extents = WORLD_SIZE / 2.0;
+    pub fn new_tree(positions: &[Vec3], masses: &[f32], world_size: f32) -> Vec<Self> {
+        let root_extents = world_size / 2.0;
         let root_center = Vec3::splat(root_extents);
         let mut max_depth = 0;
 
-        let mut nodes = vec![root_node];
-        let nodes_ptr = (&mut nodes) as *mut Vec<Self>;
-
+        let mut builder = Builder {
+            nodes: vec![Self::new_dummy()],
+        };
         for (position, mass) in positions.iter().zip(masses) {
-            max_depth = max_depth.max(nodes[0].insert(
-                *position,
-                *mass,
-                root_center,
-                root_extents,
-                nodes_ptr,
-            ));
+            max_depth = max_depth.max(builder.insert(*position, *mass, root_center, root_extents));
         }
+        let mut nodes = builder.nodes;
         nodes[0].max_depth = max_depth;
         //dbg!(max_depth); //multiply by 8, that's the max traversal
         nodes
     }
 
-    fn insert(
-        &mut self,
-        position: Vec3,
-        mass: f32,
-        self_center: Vec3,
-        self_extents: f32,
-        nodes_ptr: *mut Vec<Self>,
-    ) -> u32 {
-        if self.total_mass == 0.0 {
-            self.total_mass = mass;
-            self.center_of_mass = position;
-            self.pos_min = position;
-            self.pos_max = position;
-            self.range = (self.pos_max - self.pos_min).max_element();
-            self.is_leaf = 1;
-            self.max_depth = 0;
-
-            return (WORLD_SIZE / self_extents).ceil().log2().ceil() as u32;
-        }
-
-        let mut max_depth: u32 = 0;
-
-        let node_a_position = self.center_of_mass;
-        let node_a_mass = self.total_mass;
-        let node_b_position = position;
-        let node_b_mass = mass;
+    fn is_dummy(&self) -> bool {
+        self.is_leaf == 0 && self.child_indices == [0; 8]
+    }
 
-        self.total_mass = node_a_mass + node_b_mass;
-        self.center_of_mass =
-            ((node_a_position * node_a_mass) + (node_b_position * node_b_mass)) / self.total_mass;
-        self.pos_min = self.pos_min.min(position);
-        self.pos_max = self.pos_max.max(position);
+    fn become_leaf(&mut self, position: Vec3, mass: f32) {
+        self.total_mass = mass;
+        self.center_of_mass = position;
+        self.pos_min = position;
+        self.pos_max = position;
         self.range = (self.pos_max - self.pos_min).max_element();
-
-        let self_extents = self_extents / 2.0;
-        let nodes = unsafe { &mut *nodes_ptr };
-
-        let ci_b = node_index_for_child(self_center, node_b_position);
-        self.ensure_has_child(ci_b, nodes);
-
-        if self.is_leaf == 1 {
-            self.is_leaf = 0;
-
-            let ci_a = node_index_for_child(self_center, node_a_position);
-            self.ensure_has_child(ci_a, nodes);
-
-            max_depth = nodes[self.child_indices[ci_a] as usize].insert(
-                node_a_position,
-                node_a_mass,
-                self_center + (self_extents * extent_weights(ci_a)),
-                self_extents,
-                nodes_ptr,
-            );
-        }
-
-        max_depth = max_depth.max(nodes[self.child_indices[ci_b] as usize].insert(
-            node_b_position,
-            node_b_mass,
-            self_center + (self_extents * extent_weights(ci_b)),
-            self_extents,
-            nodes_ptr,
-        ));
-        return max_depth;
-    }
-
-    fn ensure_has_child(&mut self, ci: usize, nodes: &mut Vec<Self>) {
-        if self.child_indices[ci] == 0 {
-            let i = nodes.len();
-            nodes.push(Self::new_dummy());
-            self.child_indices[ci] = i as u32;
-        }
+        self.is_leaf = 1;
+        self.max_depth = 0;
     }
 
     fn new_dummy() -> Self {
@@ -125,6 +63,71 @@ impl OctreeNode {
     }
 }
 
+/// Arena the tree is built in. Nodes refer to each other by index, so they stay valid as the
+/// vector grows.
+struct Builder {
+    nodes: Vec<OctreeNode>,
+}
+
+impl Builder {
+    /// Sift a body down from the root, whose cell is centred on `center` and spans `extents`
+    /// either side of it. Returns the depth of the leaf it ends up in.
+    fn insert(&mut self, position: Vec3, mass: f32, mut center: Vec3, mut extents: f32) -> u32 {
+        let mut index = 0;
+        let mut depth = 0;
+        loop {
+            let node = &mut self.nodes[index];
+            if node.is_dummy() {
+                node.become_leaf(position, mass);
+                return depth;
+            }
+
+            let node_a_position = node.center_of_mass;
+            let node_a_mass = node.total_mass;
+            node.total_mass = node_a_mass + mass;
+            node.center_of_mass =
+                ((node_a_position * node_a_mass) + (position * mass)) / node.total_mass;
+            node.pos_min = node.pos_min.min(position);
+            node.pos_max = node.pos_max.max(position);
+            node.range = (node.pos_max - node.pos_min).max_element();
+
+            extents /= 2.0;
+            if node.is_leaf == 1 {
+                //a leaf's body moves down into a child of its own first
+                node.is_leaf = 0;
+                let ci_a = self.child_for(index, center, node_a_position, depth);
+                let child_a = self.ensure_has_child(index, ci_a);
+                self.nodes[child_a].become_leaf(node_a_position, node_a_mass);
+            }
+
+            //then on into the new body's child, splitting it again if it holds the old body
+            let ci_b = self.child_for(index, center, position, depth);
+            index = self.ensure_has_child(index, ci_b);
+            center += extents * extent_weights(ci_b);
+            depth += 1;
+        }
+    }
+
+    /// Which child of node `index` at `depth` a body at `position` belongs in.
+    fn child_for(&self, index: usize, center: Vec3, position: Vec3, depth: u32) -> usize {
+        if depth < MAX_SPLIT_DEPTH {
+            return node_index_for_child(center, position);
+        }
+        let children = &self.nodes[index].child_indices;
+        children.iter().position(|child| *child == 0).unwrap_or(7)
+    }
+
+    /// Index of child `ci` of node `index`, adding it as a dummy if need be.
+    fn ensure_has_child(&mut self, index: usize, ci: usize) -> usize {
+        if self.nodes[index].child_indices[ci] == 0 {
+            let i = self.nodes.len();
+            self.nodes.push(OctreeNode::new_dummy());
+            self.nodes[index].child_indices[ci] = i as u32;
+        }
+        self.nodes[index].child_indices[ci] as usize
+    }
+}
+
 fn node_index_for_child(node_center: Vec3, child_position: Vec3) -> usize {
     let mut i = 0b000;
     if child_position.x >= node_center.x {
@@ -146,3 +149,106 @@ fn extent_weights(ci: usize) -> Vec3 {
         z: -1.0 + (2.0 * ((ci & 0b001) as f32)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    const WORLD_SIZE: f32 = 1000.0;
+
+    /// `(position, mass)` of every leaf reachable from the root, in a canonical order.
+    fn leaves(nodes: &[OctreeNode]) -> Vec<[f32; 4]> {
+        let mut leaves = vec![];
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &nodes[index as usize];
+            if node.is_leaf == 1 {
+                let p = node.center_of_mass;
+                leaves.push([p.x, p.y, p.z, node.total_mass]);
+            } else {
+                stack.extend(node.child_indices.iter().filter(|child| **child != 0));
+            }
+        }
+        leaves.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        leaves
+    }
+
+    fn assert_preserved(positions: &[Vec3], masses: &[f32]) {
+        let nodes = OctreeNode::new_tree(positions, masses, WORLD_SIZE);
+
+        //every body is stored exactly once, unchanged
+        let mut given: Vec<[f32; 4]> = (positions.iter().zip(masses))
+            .map(|(p, m)| [p.x, p.y, p.z, *m])
+            .collect();
+        given.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(leaves(&nodes), given);
+
+        if positions.is_empty() {
+            assert!(nodes[0].is_dummy());
+            return;
+        }
+        let total_mass: f64 = masses.iter().map(|m| *m as f64).sum();
+        let center_of_mass = (positions.iter().zip(masses))
+            .map(|(p, m)| p.as_dvec3() * *m as f64)
+            .sum::<glam::DVec3>()
+            / total_mass;
+        let root = &nodes[0];
+        assert!((root.total_mass as f64 - total_mass).abs() <= 1e-5 * total_mass);
+        let error = (root.center_of_mass.as_dvec3() - center_of_mass).length();
+        assert!(
+            error <= 1e-5 * WORLD_SIZE as f64,
+            "centre of mass is {error} out"
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_preserved(&[], &[]);
+    }
+
+    #[test]
+    fn single_body() {
+        let nodes = OctreeNode::new_tree(&[Vec3::splat(10.0)], &[2.0], WORLD_SIZE);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].is_leaf, 1);
+        assert_eq!(nodes[0].max_depth, 0);
+    }
+
+    #[test]
+    fn coincident_bodies_stop_splitting() {
+        let positions = [Vec3::splat(10.0); 20];
+        let masses = [1.0; 20];
+        let nodes = OctreeNode::new_tree(&positions, &masses, WORLD_SIZE);
+        assert!(nodes[0].max_depth > MAX_SPLIT_DEPTH);
+        assert_preserved(&positions, &masses);
+    }
+
+    fn position() -> impl Strategy<Value = Vec3> {
+        let coordinate = -0.5 * WORLD_SIZE..1.5 * WORLD_SIZE;
+        let coordinates = (coordinate.clone(), coordinate.clone(), coordinate);
+        coordinates.prop_map(|(x, y, z)| Vec3::new(x, y, z))
+    }
+
+    fn bodies() -> impl Strategy<Value = (Vec<Vec3>, Vec<f32>)> {
+        let bodies = prop::collection::vec((position(), 0.01f32..100.0), 0..300);
+        bodies.prop_map(|bodies| bodies.into_iter().unzip())
+    }
+
+    proptest! {
+        #[test]
+        fn bodies_are_preserved((positions, masses) in bodies()) {
+            assert_preserved(&positions, &masses);
+        }
+
+        #[test]
+        fn coincident_bodies_are_preserved(
+            sites in prop::collection::vec(position(), 1..4),
+            bodies in prop::collection::vec((any::<prop::sample::Index>(), 0.01f32..100.0), 1..300),
+        ) {
+            let positions: Vec<Vec3> = bodies.iter().map(|(site, _)| *site.get(&sites)).collect();
+            let masses: Vec<f32> = bodies.iter().map(|(_, mass)| *mass).collect();
+            assert_preserved(&positions, &masses);
+        }
+    }
+}
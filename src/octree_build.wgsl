@@ -0,0 +1,510 @@
+// Builds the Barnes-Hut octree from the positions on the GPU, see `GpuOctree` in
+// src/gpu_octree.rs. Follows octree_node.wgsl.
+//
+// The root is the tightest cube around the bodies, found afresh every build. Bodies are then
+// sorted by Morton key, so every cell's bodies are a contiguous run of the sorted
+// order, and the levels two bodies' cells share are the length of their keys' common prefix.
+// Each node is then emitted by the first body of its run, which puts them in depth-first order.
+// Cells holding at most `tree.leaf_size` bodies, or `tree.max_depth` deep, are leaves; cells with
+// a single child hold the same bodies as it, so they're left out, leaving at most 2n nodes for n
+// bodies. The sorted bodies follow them from node 2n on, as the Leaf-Lists'
+// blocks of Leaf-Body nodes.
+
+// A body's Morton key, split into two halves of HALF_LEVELS levels each, most significant
+// first, and the body's index
+struct Item {
+    hi: u32,
+    lo: u32,
+    body: u32,
+};
+
+struct TreeParams {
+    quadrupole: u32,
+    leaf_size: u32,
+    max_depth: u32,
+};
+
+// The root cell, from its lowest corner
+struct Cell {
+    corner: vec3<f32>,
+    size: f32,
+};
+
+// Settings of one dispatch, bound at a dynamic offset: the radix sort pass and the length of the
+// counts to scan, or the depth whose nodes to sum
+struct Pass {
+    index: u32,
+    length: u32,
+};
+
+let TREE_WG_SIZE: u32 = 256u;
+let HALF_LEVELS: u32 = 10u;
+let RADIX_BITS: u32 = 4u;
+let RADIX: u32 = 16u;
+
+@group(0) @binding(0) var<storage, read> positions: array<vec3<f32>>;
+@group(0) @binding(1) var<storage, read> masses: array<f32>;
+@group(0) @binding(2) var<uniform> tree: TreeParams;
+@group(0) @binding(3) var<storage, read_write> octree: array<OctreeNode>;
+// Depths of the nodes each sorted body emits, as a bitmask
+@group(0) @binding(4) var<storage, read_write> heads: array<u32>;
+// Digit counts of the radix sort, then the index of each sorted body's first node
+@group(0) @binding(5) var<storage, read_write> counts: array<u32>;
+@group(0) @binding(6) var<storage, read_write> root: Cell;
+@group(1) @binding(0) var<storage, read> items_in: array<Item>;
+@group(1) @binding(1) var<storage, read_write> items_out: array<Item>;
+@group(1) @binding(2) var<uniform> tree_pass: Pass;
+
+var<workgroup> digits: array<u32, 256>;
+var<workgroup> histogram: array<atomic<u32>, 16>;
+var<workgroup> sums: array<u32, 256>;
+var<workgroup> lows: array<vec3<f32>, 256>;
+var<workgroup> highs: array<vec3<f32>, 256>;
+
+// Spread the low HALF_LEVELS bits of each coordinate out to every third bit, x first
+fn interleave(cell: vec3<u32>) -> u32 {
+    var x = cell & vec3(0x3ffu);
+    x = (x | (x << vec3(16u))) & vec3(0x30000ffu);
+    x = (x | (x << vec3(8u))) & vec3(0x300f00fu);
+    x = (x | (x << vec3(4u))) & vec3(0x30c30c3u);
+    x = (x | (x << vec3(2u))) & vec3(0x9249249u);
+    return (x.x << 2u) | (x.y << 1u) | x.z;
+}
+
+// Digit of `item`'s key sorted on by radix sort pass `index`, least significant first
+fn digit(item: Item, index: u32) -> u32 {
+    // halves are 3 * HALF_LEVELS bits long, so a digit can take bits of both
+    let shift = index * RADIX_BITS;
+    let lo_bits = 3u * HALF_LEVELS;
+    if shift >= lo_bits {
+        return (item.hi >> (shift - lo_bits)) & (RADIX - 1u);
+    }
+    return ((item.lo >> shift) | (item.hi << (lo_bits - shift))) & (RADIX - 1u);
+}
+
+// Octant of the cell at `depth` that `item`'s body is in, numbered as by `node_index_for_child`
+fn octant(item: Item, depth: u32) -> u32 {
+    if depth < HALF_LEVELS {
+        return (item.hi >> (3u * (HALF_LEVELS - 1u - depth))) & 7u;
+    }
+    return (item.lo >> (3u * (2u * HALF_LEVELS - 1u - depth))) & 7u;
+}
+
+// Levels below the root that the cells of sorted bodies `i` and `j` share, up to the
+// 2 * HALF_LEVELS that keys hold
+fn shared_levels(i: u32, j: u32) -> i32 {
+    let a = items_in[i];
+    let b = items_in[j];
+    if a.hi != b.hi {
+        return i32(HALF_LEVELS - 1u - firstLeadingBit(a.hi ^ b.hi) / 3u);
+    }
+    if a.lo != b.lo {
+        return i32(2u * HALF_LEVELS - 1u - firstLeadingBit(a.lo ^ b.lo) / 3u);
+    }
+    return i32(2u * HALF_LEVELS);
+}
+
+// Shallowest depth at which sorted body `i` starts a run
+fn first_depth(i: u32) -> i32 {
+    if i == 0u {
+        return 0;
+    }
+    return shared_levels(i - 1u, i) + 1;
+}
+
+// Whether a cell `depth` deep holding `length` bodies is a leaf, as by `TreeShape::is_leaf`
+fn is_leaf(length: u32, depth: i32) -> bool {
+    return length <= tree.leaf_size || depth >= i32(tree.max_depth);
+}
+
+// First sorted body after `i` that shares at most `depth` levels with it, or n
+fn run_end(i: u32, depth: i32, n: u32) -> u32 {
+    var low = i + 1u;
+    var high = n;
+    loop {
+        if low >= high {
+            break;
+        }
+        let mid = (low + high) / 2u;
+        if shared_levels(i, mid) > depth {
+            low = mid + 1u;
+        } else {
+            high = mid;
+        }
+    }
+    return low;
+}
+
+// First sorted body of the run at `depth` that holds sorted body `i`
+fn run_start(i: u32, depth: i32) -> u32 {
+    var low = 0u;
+    var high = i;
+    loop {
+        if low >= high {
+            break;
+        }
+        let mid = (low + high) / 2u;
+        if shared_levels(mid, i) < depth {
+            low = mid + 1u;
+        } else {
+            high = mid;
+        }
+    }
+    return low;
+}
+
+// Depths of the nodes sorted body `i` emits: the root, every cell it starts that splits, and
+// the leaf it ends in; nothing if it's inside a leaf that another body starts
+fn emitted_depths(i: u32, n: u32) -> u32 {
+    var depth = first_depth(i);
+    if i > 0u {
+        // the cell the run starts in is the deepest of the ancestors, so the fullest to be a leaf
+        let parent = depth - 1;
+        if is_leaf(run_end(i, parent - 1, n) - run_start(i, parent), parent) {
+            return 0u;
+        }
+    }
+    var mask = 0u;
+    loop {
+        let leaf = is_leaf(run_end(i, depth - 1, n) - i, depth);
+        var emit = depth == 0 || leaf;
+        if !emit {
+            let end = run_end(i, depth, n);
+            emit = end < n && shared_levels(i, end) == depth;
+        }
+        if emit {
+            mask |= 1u << u32(depth);
+        }
+        if leaf {
+            break;
+        }
+        depth += 1;
+    }
+    return mask;
+}
+
+// The first node run `i` emits, or 0, which ends a walk, if it's past the last
+fn first_node(i: u32, n: u32) -> u32 {
+    if i >= n {
+        return 0u;
+    }
+    return counts[i];
+}
+
+fn leaf_body(body: u32) -> OctreeNode {
+    var node: OctreeNode;
+    node.center_of_mass = positions[body];
+    node.pos_min = positions[body];
+    node.pos_max = positions[body];
+    node.total_mass = masses[body];
+    node.node_type = NODETYPE_LEAFBODY;
+    return node;
+}
+
+// Find the root cell, in a single workgroup, as `root_cell` in src/octree_maxdepth.rs does
+@compute
+@workgroup_size(256)
+fn bound_bodies(@builtin(local_invocation_index) local: u32) {
+    var low = vec3(1e38);
+    var high = vec3(-1e38);
+    for (var i = local; i < arrayLength(&masses); i += TREE_WG_SIZE) {
+        low = min(low, positions[i]);
+        high = max(high, positions[i]);
+    }
+    lows[local] = low;
+    highs[local] = high;
+    workgroupBarrier();
+    for (var offset = TREE_WG_SIZE / 2u; offset > 0u; offset /= 2u) {
+        if local < offset {
+            lows[local] = min(lows[local], lows[local + offset]);
+            highs[local] = max(highs[local], highs[local + offset]);
+        }
+        workgroupBarrier();
+    }
+    if local == 0u {
+        let half_widths = (highs[0] - lows[0]) / 2.0;
+        let extents = max(max(half_widths.x, max(half_widths.y, half_widths.z)), 1.17549435e-38);
+        root = Cell((lows[0] + highs[0]) / 2.0 - extents, 2.0 * extents);
+    }
+}
+
+@compute
+@workgroup_size(256)
+fn compute_keys(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i = global_invocation_id.x;
+    if i >= arrayLength(&masses) {
+        return;
+    }
+    // bodies on the root's far faces are binned into the cells below them
+    let cells = f32(1u << (2u * HALF_LEVELS));
+    let scaled = floor((positions[i] - root.corner) / root.size * cells);
+    let cell = vec3<u32>(clamp(scaled, vec3(0.0), vec3(cells - 1.0)));
+    items_out[i] = Item(interleave(cell >> vec3(HALF_LEVELS)), interleave(cell), i);
+}
+
+// Count each workgroup's digits, digit-major, so that scanning the counts gives every
+// workgroup's offset for each digit in stable order
+@compute
+@workgroup_size(256)
+fn sort_count(
+    @builtin(local_invocation_index) local: u32,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+    let n = arrayLength(&masses);
+    let n_workgroups = (n + TREE_WG_SIZE - 1u) / TREE_WG_SIZE;
+    if local < RADIX {
+        atomicStore(&histogram[local], 0u);
+    }
+    workgroupBarrier();
+    let i = workgroup_id.x * TREE_WG_SIZE + local;
+    if i < n {
+        atomicAdd(&histogram[digit(items_in[i], tree_pass.index)], 1u);
+    }
+    workgroupBarrier();
+    if local < RADIX {
+        counts[local * n_workgroups + workgroup_id.x] = atomicLoad(&histogram[local]);
+    }
+}
+
+// Exclusive prefix sum of the first `tree_pass.length` counts, in a single workgroup
+@compute
+@workgroup_size(256)
+fn scan(@builtin(local_invocation_index) local: u32) {
+    let length = tree_pass.length;
+    let chunk = (length + TREE_WG_SIZE - 1u) / TREE_WG_SIZE;
+    let start = min(local * chunk, length);
+    let end = min(start + chunk, length);
+    var sum = 0u;
+    for (var i = start; i < end; i += 1u) {
+        sum += counts[i];
+    }
+    sums[local] = sum;
+    workgroupBarrier();
+    for (var offset = 1u; offset < TREE_WG_SIZE; offset *= 2u) {
+        var total = sums[local];
+        if local >= offset {
+            total += sums[local - offset];
+        }
+        workgroupBarrier();
+        sums[local] = total;
+        workgroupBarrier();
+    }
+    var running = sums[local] - sum;
+    for (var i = start; i < end; i += 1u) {
+        let count = counts[i];
+        counts[i] = running;
+        running += count;
+    }
+}
+
+// Move each item to its digit's offset, after the items with the same digit before it
+@compute
+@workgroup_size(256)
+fn sort_scatter(
+    @builtin(local_invocation_index) local: u32,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+    let n = arrayLength(&masses);
+    let n_workgroups = (n + TREE_WG_SIZE - 1u) / TREE_WG_SIZE;
+    let i = workgroup_id.x * TREE_WG_SIZE + local;
+    var item: Item;
+    var d = RADIX;
+    if i < n {
+        item = items_in[i];
+        d = digit(item, tree_pass.index);
+    }
+    digits[local] = d;
+    workgroupBarrier();
+    if i >= n {
+        return;
+    }
+    var rank = 0u;
+    for (var j = 0u; j < local; j += 1u) {
+        if digits[j] == d {
+            rank += 1u;
+        }
+    }
+    items_out[counts[d * n_workgroups + workgroup_id.x] + rank] = item;
+}
+
+@compute
+@workgroup_size(256)
+fn mark_heads(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i = global_invocation_id.x;
+    let n = arrayLength(&masses);
+    if i >= n {
+        return;
+    }
+    let mask = emitted_depths(i, n);
+    heads[i] = mask;
+    counts[i] = countOneBits(mask);
+}
+
+// Write each sorted body's nodes, with no children yet, and its Leaf-Body node in the block
+// after them
+@compute
+@workgroup_size(256)
+fn emit_nodes(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i = global_invocation_id.x;
+    let n = arrayLength(&masses);
+    if i >= n {
+        return;
+    }
+    let mask = heads[i];
+    let body = items_in[i].body;
+    // the leaf is the deepest, -1 if there are none
+    let leaf = i32(firstLeadingBit(mask));
+    var index = counts[i];
+    for (var depth = 0; depth <= leaf; depth += 1) {
+        if (mask & (1u << u32(depth))) == 0u {
+            continue;
+        }
+        let end = run_end(i, depth - 1, n);
+        var node: OctreeNode;
+        node.node_type = NODETYPE_INTERIOR;
+        // the nodes are in depth-first order, so the first child is next, and the node after
+        // the last descendant is the first of the run's end
+        node.next = index + 1u;
+        node.skip = first_node(end, n);
+        if depth == leaf {
+            if end - i == 1u {
+                node = leaf_body(body);
+                node.next = first_node(end, n);
+                node.skip = node.next;
+            } else {
+                node.node_type = NODETYPE_LEAFLIST;
+                node.child_indices[0] = 2u * n + i;
+                node.child_indices[1] = end - i;
+                node.next = 2u * n + i;
+            }
+        }
+        octree[index] = node;
+        index += 1u;
+    }
+    // in the block, go on to the next body of the same Leaf-List, which starts no nodes, or
+    // else wherever the list goes on to
+    var block_body = leaf_body(body);
+    block_body.skip = first_node(i + 1u, n);
+    if i + 1u < n && heads[i + 1u] == 0u {
+        block_body.skip = 2u * n + i + 1u;
+    }
+    block_body.next = block_body.skip;
+    octree[2u * n + i] = block_body;
+}
+
+// Hang each node from its nearest emitted ancestor
+@compute
+@workgroup_size(256)
+fn link_nodes(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i = global_invocation_id.x;
+    let n = arrayLength(&masses);
+    if i >= n {
+        return;
+    }
+    let mask = heads[i];
+    let first = first_depth(i);
+    var index = counts[i];
+    for (var depth = 0; depth <= i32(tree.max_depth); depth += 1) {
+        if (mask & (1u << u32(depth))) == 0u {
+            continue;
+        }
+        var ancestor = depth - 1;
+        loop {
+            if ancestor < 0 {
+                break;
+            }
+            var j = i;
+            if ancestor < first {
+                j = run_start(i, ancestor);
+            }
+            let bit = 1u << u32(ancestor);
+            if (heads[j] & bit) != 0u {
+                let parent = counts[j] + countOneBits(heads[j] & (bit - 1u));
+                octree[parent].child_indices[octant(items_in[i], u32(ancestor))] = index;
+                break;
+            }
+            ancestor -= 1;
+        }
+        index += 1u;
+    }
+}
+
+// Sum the masses, centres of mass and bounds of the Interior and Leaf-List nodes at depth
+// `tree_pass.index`, whose children are all deeper and so already summed
+@compute
+@workgroup_size(256)
+fn sum_nodes(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i = global_invocation_id.x;
+    let depth = tree_pass.index;
+    if i >= arrayLength(&masses) || (heads[i] & (1u << depth)) == 0u {
+        return;
+    }
+    let mask = heads[i];
+    let index = counts[i] + countOneBits(mask & ((1u << depth) - 1u));
+    var node = octree[index];
+    if node.node_type == NODETYPE_LEAFBODY {
+        return;
+    }
+
+    var first = 0u;
+    var end = 8u;
+    if node.node_type == NODETYPE_LEAFLIST {
+        first = node.child_indices[0];
+        end = first + node.child_indices[1];
+    }
+    var total_mass = 0.0;
+    var moment = vec3(0.0);
+    var pos_min = vec3(1e38);
+    var pos_max = vec3(-1e38);
+    for (var c = first; c < end; c += 1u) {
+        var child = c;
+        if node.node_type == NODETYPE_INTERIOR {
+            child = node.child_indices[c];
+            if child == 0u {
+                continue;
+            }
+        }
+        let child_node = octree[child];
+        total_mass += child_node.total_mass;
+        moment += child_node.total_mass * child_node.center_of_mass;
+        pos_min = min(pos_min, child_node.pos_min);
+        pos_max = max(pos_max, child_node.pos_max);
+    }
+    node.total_mass = total_mass;
+    // with no mass to weigh by, take the middle of the bounds, as `weigh` in
+    // src/octree_maxdepth.rs does, rather than a NaN that would spread to every ancestor
+    if total_mass == 0.0 {
+        node.center_of_mass = (pos_min + pos_max) / 2.0;
+    } else {
+        node.center_of_mass = moment / total_mass;
+    }
+    node.pos_min = pos_min;
+    node.pos_max = pos_max;
+    let extent = pos_max - pos_min;
+    node.range = max(extent.x, max(extent.y, extent.z));
+
+    // the children's quadrupole moments, moved to this centre of mass by the parallel axis
+    // theorem
+    if tree.quadrupole != 0u {
+        var diagonal = vec3(0.0);
+        var off_diagonal = vec3(0.0);
+        for (var c = first; c < end; c += 1u) {
+            var child = c;
+            if node.node_type == NODETYPE_INTERIOR {
+                child = node.child_indices[c];
+                if child == 0u {
+                    continue;
+                }
+            }
+            let child_node = octree[child];
+            let d = child_node.center_of_mass - node.center_of_mass;
+            let m = child_node.total_mass;
+            diagonal += child_node.quadrupole_diagonal + m * (3.0 * d * d - vec3(dot(d, d)));
+            off_diagonal += child_node.quadrupole_off_diagonal
+                + 3.0 * m * vec3(d.x * d.y, d.x * d.z, d.y * d.z);
+        }
+        node.quadrupole_diagonal = diagonal;
+        node.quadrupole_off_diagonal = off_diagonal;
+    }
+    octree[index] = node;
+}
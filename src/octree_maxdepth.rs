@@ -1,212 +1,370 @@
-use crate::WORLD_SIZE;
+use crate::octree_morton;
+use crate::Parameters;
+use clap::ValueEnum;
 use encase::ShaderType;
 use glam::Vec3;
+use serde::Deserialize;
 
-//global constant, or base it upon our N_BODIES, not sure, probably the latter
-//but also, to account for floating point error stuff, maybe we just actually cap it?
-const MAX_DEPTH: u32 = 16;
-const NODETYPE_DUMMY: u32 = 0;
-const NODETYPE_LEAFBODY: u32 = 1;
-const NODETYPE_LEAFLIST: u32 = 2;
-const NODETYPE_INTERIOR: u32 = 3;
+/// Deepest `max_depth` that every builder supports; the GPU's Morton keys hold 20 levels.
+pub const MAX_TREE_DEPTH: u32 = 20;
+pub(crate) const NODETYPE_DUMMY: u32 = 0;
+pub(crate) const NODETYPE_LEAFBODY: u32 = 1;
+pub(crate) const NODETYPE_LEAFLIST: u32 = 2;
+pub(crate) const NODETYPE_INTERIOR: u32 = 3;
+
+/// How the CPU Barnes-Hut octree is built before each force evaluation. Both give the same
+/// tree, but for bodies right on a cell boundary and the order of the nodes. `GpuBhSimulation`
+/// builds its own on the GPU.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeBuilder {
+    /// Insert the bodies one at a time, sifting each down from the root
+    #[default]
+    Insertion,
+    /// Sort the bodies by Morton key, then split and sum the tree a level at a time in parallel
+    Morton,
+}
+
+impl TreeBuilder {
+    pub fn build(self, positions: &[Vec3], masses: &[f32], shape: TreeShape) -> Vec<OctreeNode> {
+        let mut nodes = match self {
+            TreeBuilder::Insertion => OctreeNode::new_tree(positions, masses, shape),
+            TreeBuilder::Morton => octree_morton::new_tree(positions, masses, shape),
+        };
+        thread_ropes(&mut nodes);
+        nodes
+    }
+}
+
+/// Where cells stop splitting: once they hold at most `leaf_size` bodies, which then share a
+/// Leaf-List unless there's just one, or once they're `max_depth` levels deep, however many
+/// bodies they hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeShape {
+    pub leaf_size: u32,
+    pub max_depth: u32,
+}
+
+impl TreeShape {
+    pub fn new(parameters: &Parameters) -> Self {
+        Self {
+            leaf_size: parameters.leaf_size,
+            max_depth: parameters.max_depth,
+        }
+    }
+
+    /// Whether a cell `depth` levels deep holding `n_bodies` is a leaf.
+    pub(crate) fn is_leaf(&self, n_bodies: usize, depth: u32) -> bool {
+        n_bodies <= self.leaf_size as usize || depth == self.max_depth
+    }
+}
+
+impl Default for TreeShape {
+    fn default() -> Self {
+        Self::new(&Parameters::default())
+    }
+}
+
+/// Centre and half-width of the root cell: the tightest cube around every position, so bodies
+/// can roam anywhere. Never zero wide, so positions can be scaled by it.
+pub(crate) fn root_cell(positions: &[Vec3]) -> (Vec3, f32) {
+    let low = positions.iter().fold(Vec3::splat(f32::INFINITY), |low, p| low.min(*p));
+    let high = positions.iter().fold(Vec3::splat(f32::NEG_INFINITY), |high, p| high.max(*p));
+    let extents = ((high - low) / 2.0).max_element().max(f32::MIN_POSITIVE);
+    ((low + high) / 2.0, extents)
+}
 
 #[derive(ShaderType)]
 pub struct OctreeNode {
-    center_of_mass: Vec3,
-    pos_min: Vec3,
-    pos_max: Vec3,
-    range: f32,
-    total_mass: f32,
-    child_indices: [u32; 8],
-    node_type: u32,
+    pub(crate) center_of_mass: Vec3,
+    pub(crate) pos_min: Vec3,
+    pub(crate) pos_max: Vec3,
+    //traceless quadrupole moment about the centre of mass, sum of m (3 x x^T - |x|^2 I): its
+    //xx, yy and zz components, then xy, xz and yz; left at zero unless `add_quadrupoles` is run
+    pub(crate) quadrupole_diagonal: Vec3,
+    pub(crate) quadrupole_off_diagonal: Vec3,
+    pub(crate) range: f32,
+    pub(crate) total_mass: f32,
+    pub(crate) child_indices: [u32; 8],
+    pub(crate) node_type: u32,
+    //rope pointers, so the tree can be walked without a stack: where the walk goes on when this
+    //node is opened, its first child (a Leaf-List's first body), and when it's done with, the
+    //node after its last descendant; the root is never gone on to, so 0 ends the walk
+    pub(crate) next: u32,
+    pub(crate) skip: u32,
     //max_depth: u32,
 }
 
 impl OctreeNode {
-    pub fn new_tree(positions: &[Vec3], masses: &[f32]) -> Vec<Self> {
-        let root_node = Self::new_dummy();
-        let root_extents = WORLD_SIZE / 2.0;
-        let root_center = Vec3::splat(root_extents);
+    pub fn new_tree(positions: &[Vec3], masses: &[f32], shape: TreeShape) -> Vec<Self> {
+        let (root_center, root_extents) = root_cell(positions);
         //let mut max_depth = 0;
 
-        let mut nodes = vec![root_node];
-        let nodes_ptr = (&mut nodes) as *mut Vec<Self>;
-		let mut leaf_list_children:Vec<Vec<Self>> = vec![];
-        let leaf_list_children_ptr = (&mut leaf_list_children) as *mut Vec<Vec<Self>>;
-		let mut leaf_lists:Vec<*mut Self> = vec![];
-		let leaf_lists_ptr = (&mut leaf_lists) as *mut Vec<*mut Self>;
-
+        let mut builder = Builder {
+            shape,
+            nodes: vec![Self::new_dummy()],
+            leaf_list_children: vec![],
+            leaf_lists: vec![],
+        };
         for (position, mass) in positions.iter().zip(masses) {
-            /*max_depth = max_depth.max(nodes[0].insert(
-                *position,
-                *mass,
-                root_center,
-                root_extents,
-                nodes_ptr,
-            ));*/
-            nodes[0].insert(*position, *mass, root_center, root_extents, nodes_ptr, leaf_list_children_ptr, leaf_lists_ptr, 0);
-        }
-		
-		//cleanup: add all leaf-body nodes, each stored in vectors inside leaf_list_children, as contiguous blocks into nodes
-		for (i,leaf_children) in leaf_list_children.iter_mut().enumerate() {
-			let leaf_list = unsafe {&mut *leaf_lists[i]};
-			leaf_list.child_indices[0] = nodes.len() as u32;
-			leaf_list.child_indices[1] = leaf_children.len() as u32;
-			nodes.append(leaf_children);
-		}	
-		
+            builder.insert(*position, *mass, 0, 0, root_center, root_extents);
+        }
         //nodes[0].max_depth = max_depth;
         //dbg!(max_depth); //multiply by 8, that's the max traversal
-        nodes
+        builder.finish()
     }
 
-    fn insert(
-        &mut self,
-        position: Vec3,
-        mass: f32,
-        self_center: Vec3,
-        self_extents: f32,
-        nodes_ptr: *mut Vec<Self>,
-		leaf_list_children_ptr: *mut Vec<Vec<Self>>,
-		leaf_lists_ptr: *mut Vec<*mut Self>,
-        curr_depth: u32,
-    ) /*-> u32*/
-    {
-        if self.node_type == NODETYPE_DUMMY {
-            self.total_mass = mass;
-            self.center_of_mass = position;
-            self.pos_min = position;
-            self.pos_max = position;
-            self.range = (self.pos_max - self.pos_min).max_element();
-            self.node_type = NODETYPE_LEAFBODY;
-            //self.max_depth = 0;
-
-            //return (WORLD_SIZE / self_extents).ceil().log2().ceil() as u32;
-			return;
-        }
-
-        //let mut max_depth: u32 = 0;
-
-        //if non-dummy, always need to compute new CoM, total mass, etc.
-        let node_a_position = self.center_of_mass;
-        let node_a_mass = self.total_mass;
-        let node_b_position = position;
-        let node_b_mass = mass;
-
-        self.total_mass = node_a_mass + node_b_mass;
-        self.center_of_mass =
-            ((node_a_position * node_a_mass) + (node_b_position * node_b_mass)) / self.total_mass;
-        self.pos_min = self.pos_min.min(position);
-        self.pos_max = self.pos_max.max(position);
-        self.range = (self.pos_max - self.pos_min).max_element();
-
-        if curr_depth == MAX_DEPTH {
-            //if we are at MAX_DEPTH, prevent degeneracy, we must become a Leaf-List node
-			let leaf_list_children = unsafe { &mut *leaf_list_children_ptr };
-			let leaf_lists = unsafe { &mut *leaf_lists_ptr };
-			
-			//as a Leaf-List:
-			//	our leaf list index (into leaf_list_children_ptr) is: self.child_indices[0];
-			//	our leaf list length (vector len within leaf_list_children_ptr) is: self.child_indices[1];
-			//	a reference to ourselves will be stored in leaf_lists(_ptr) at the matching index
-			//		this is essentially a sloppier approach than making a struct holding a reference to us and the vec
-			
-            if self.node_type != NODETYPE_LEAFLIST { //if not yet a Leaf-List, must have been a Leaf-Body -- initialize Leaf-List
-				self.node_type = NODETYPE_LEAFLIST;
-				self.child_indices[0] = leaf_list_children.len() as u32;
-				self.child_indices[1] = 0;
-				leaf_list_children.push(Vec::new()); //TODO: Jasmine check this!
-				leaf_lists.push(self as *mut Self);
-			}
-			//already set-up, simple insertion time
-			//create new Leaf-Body node for new body
-			let mut child = Self::new_dummy();
-			child.insert(
-				node_a_position,
-				node_a_mass,
-				self_center,
-				self_extents,
-				nodes_ptr,
-				leaf_list_children_ptr,
-				leaf_lists_ptr,
-				0
-			);
-			//delay pushing it to nodes, instead pushing to leaf_list_children, and record size for GPU processing in [1]
-			leaf_list_children[self.child_indices[0] as usize].push(child);
-			self.child_indices[1] += 1;
-        } else {
-            //we are not at MAX_DEPTH, and we aren't a Dummy, so we should sift our child down
-            let self_extents = self_extents / 2.0;
-            let nodes = unsafe { &mut *nodes_ptr };
-
-            let ci_b = node_index_for_child(self_center, node_b_position);
-            self.ensure_has_child(ci_b, nodes);
-
-            if self.node_type == NODETYPE_LEAFBODY {
-                //if we are a Leaf-Body, we need to sift down our current body as well as the new one
-                //we aren't at MAX_DEPTH, so we become an Interior
-                self.node_type = NODETYPE_INTERIOR;
-                let ci_a = node_index_for_child(self_center, node_a_position);
-                self.ensure_has_child(ci_a, nodes);
-
-                /*max_depth = */
-                nodes[self.child_indices[ci_a] as usize].insert(
-                    node_a_position,
-                    node_a_mass,
-                    self_center + (self_extents * extent_weights(ci_a)),
-                    self_extents,
-                    nodes_ptr,
-					leaf_list_children_ptr,
-					leaf_lists_ptr,
-                    curr_depth + 1,
-                );
-            }
+    pub(crate) fn new_leaf_body(position: Vec3, mass: f32) -> Self {
+        let mut node = Self::new_dummy();
+        node.become_leaf_body(position, mass);
+        node
+    }
 
-            /*max_depth = max_depth.max(nodes[self.child_indices[ci_b] as usize].insert(
-                node_b_position,
-                node_b_mass,
-                self_center + (self_extents * extent_weights(ci_b)),
-                self_extents,
-                nodes_ptr,
-            ));
-            return max_depth;*/
+    pub(crate) fn become_leaf_body(&mut self, position: Vec3, mass: f32) {
+        self.total_mass = mass;
+        self.center_of_mass = position;
+        self.pos_min = position;
+        self.pos_max = position;
+        self.range = (self.pos_max - self.pos_min).max_element();
+        self.node_type = NODETYPE_LEAFBODY;
+    }
 
-            //now, whether we were Interior or Leaf-Body before, we are now Interior, and can sift our new child easily
-            nodes[self.child_indices[ci_b] as usize].insert(
-                node_b_position,
-                node_b_mass,
-                self_center + (self_extents * extent_weights(ci_b)),
-                self_extents,
-                nodes_ptr,
-				leaf_list_children_ptr,
-				leaf_lists_ptr,
-                curr_depth + 1,
-            );
+    /// Take the total mass, centre of mass and bounds of the non-empty `children`.
+    pub(crate) fn sum_children<'a>(&mut self, children: impl Iterator<Item = &'a Self>) {
+        let mut total_mass = 0.0;
+        let mut moment = Vec3::ZERO;
+        self.pos_min = Vec3::splat(f32::INFINITY);
+        self.pos_max = Vec3::splat(f32::NEG_INFINITY);
+        for child in children {
+            total_mass += child.total_mass;
+            moment += child.center_of_mass * child.total_mass;
+            self.pos_min = self.pos_min.min(child.pos_min);
+            self.pos_max = self.pos_max.max(child.pos_max);
         }
+        self.total_mass = total_mass;
+        self.weigh(moment);
+        self.range = (self.pos_max - self.pos_min).max_element();
     }
 
-    fn ensure_has_child(&mut self, ci: usize, nodes: &mut Vec<Self>) {
-        if self.child_indices[ci] == 0 {
-            let i = nodes.len();
-            nodes.push(Self::new_dummy());
-            self.child_indices[ci] = i as u32;
-        }
+    /// Take the centre of mass from the mass `moment` about the origin, or the middle of the
+    /// bounds if there's no mass to weigh it by, as its NaN would spread to every ancestor.
+    fn weigh(&mut self, moment: Vec3) {
+        self.center_of_mass = if self.total_mass == 0.0 {
+            (self.pos_min + self.pos_max) / 2.0
+        } else {
+            moment / self.total_mass
+        };
+    }
+
+    /// Quadrupole moment of this node about `center`, by the parallel axis theorem.
+    fn quadrupole_about(&self, center: Vec3) -> (Vec3, Vec3) {
+        let d = self.center_of_mass - center;
+        let m = self.total_mass;
+        let diagonal = self.quadrupole_diagonal + m * (3.0 * d * d - Vec3::splat(d.dot(d)));
+        let off_diagonal = Vec3::new(d.x * d.y, d.x * d.z, d.y * d.z);
+        (diagonal, self.quadrupole_off_diagonal + 3.0 * m * off_diagonal)
     }
 
-    fn new_dummy() -> Self {
+    pub(crate) fn new_dummy() -> Self {
         Self {
             center_of_mass: Vec3::ZERO,
             pos_min: Vec3::ZERO,
             pos_max: Vec3::ZERO,
+            quadrupole_diagonal: Vec3::ZERO,
+            quadrupole_off_diagonal: Vec3::ZERO,
             range: 0.0,
             total_mass: 0.0,
             child_indices: [0; 8],
             node_type: NODETYPE_DUMMY,
+            next: 0,
+            skip: 0,
             //max_depth: 0,
         }
     }
 }
 
+/// Fill in the `next` and `skip` rope pointers of a built tree, whatever order its nodes are in.
+fn thread_ropes(nodes: &mut [OctreeNode]) {
+    let mut stack = vec![(0, 0)];
+    while let Some((index, skip)) = stack.pop() {
+        let node = &mut nodes[index];
+        let children: Vec<u32> = match node.node_type {
+            NODETYPE_LEAFLIST => {
+                let [first, len, ..] = node.child_indices;
+                (first..first + len).collect()
+            }
+            NODETYPE_INTERIOR => (node.child_indices.iter())
+                .filter(|child| **child != 0)
+                .copied()
+                .collect(),
+            _ => vec![],
+        };
+        node.next = children.first().copied().unwrap_or(skip);
+        node.skip = skip;
+        //each child goes on to its next sibling, and the last to wherever this node does
+        let skips = children.iter().skip(1).copied().chain([skip]);
+        stack.extend(children.iter().zip(skips).map(|(child, skip)| (*child as usize, skip)));
+    }
+}
+
+/// Fill in the quadrupole moments of a built tree's Interior and Leaf-List nodes. Both builders
+/// put children after their parents, so summing from the end reaches every child first.
+pub(crate) fn add_quadrupoles(nodes: &mut [OctreeNode]) {
+    for index in (0..nodes.len()).rev() {
+        let node = &nodes[index];
+        let children: Vec<usize> = match node.node_type {
+            NODETYPE_LEAFLIST => {
+                let [first, len, ..] = node.child_indices;
+                (first as usize..(first + len) as usize).collect()
+            }
+            NODETYPE_INTERIOR => (node.child_indices.iter())
+                .filter(|child| **child != 0)
+                .map(|child| *child as usize)
+                .collect(),
+            _ => continue,
+        };
+        let center = node.center_of_mass;
+        let mut diagonal = Vec3::ZERO;
+        let mut off_diagonal = Vec3::ZERO;
+        for child in children {
+            let (child_diagonal, child_off_diagonal) = nodes[child].quadrupole_about(center);
+            diagonal += child_diagonal;
+            off_diagonal += child_off_diagonal;
+        }
+        nodes[index].quadrupole_diagonal = diagonal;
+        nodes[index].quadrupole_off_diagonal = off_diagonal;
+    }
+}
+
+/// Arena the tree is built in. Nodes refer to each other by index, so they stay valid as the
+/// vectors grow.
+struct Builder {
+    shape: TreeShape,
+    nodes: Vec<OctreeNode>,
+    //the Leaf-Body nodes of each Leaf-List, kept aside until every body is in
+    leaf_list_children: Vec<Vec<OctreeNode>>,
+    //index into nodes of the Leaf-List owning the matching leaf_list_children entry
+    leaf_lists: Vec<usize>,
+}
+
+impl Builder {
+    /// Sift a body down from node `index`, `depth` levels deep, whose cell is centred on
+    /// `center` and spans `extents` either side of it.
+    fn insert(
+        &mut self,
+        position: Vec3,
+        mass: f32,
+        mut index: usize,
+        mut depth: u32,
+        mut center: Vec3,
+        mut extents: f32,
+    ) {
+        loop {
+            let node = &mut self.nodes[index];
+            if node.node_type == NODETYPE_DUMMY {
+                node.become_leaf_body(position, mass);
+                return;
+            }
+
+            //if non-dummy, always need to compute new CoM, total mass, etc.
+            let node_a_position = node.center_of_mass;
+            let node_a_mass = node.total_mass;
+            node.total_mass = node_a_mass + mass;
+            node.pos_min = node.pos_min.min(position);
+            node.pos_max = node.pos_max.max(position);
+            node.weigh((node_a_position * node_a_mass) + (position * mass));
+            node.range = (node.pos_max - node.pos_min).max_element();
+
+            //bodies held so far, if we are a leaf
+            let held = match node.node_type {
+                NODETYPE_LEAFBODY => 1,
+                NODETYPE_LEAFLIST => node.child_indices[1] as usize,
+                _ => 0,
+            };
+            if held != 0 && self.shape.is_leaf(held + 1, depth) {
+                //if the leaf has room, or we are at max_depth, where we must not split to prevent
+                //degeneracy, we must become a Leaf-List node
+                //as a Leaf-List:
+                //	our leaf list index (into leaf_list_children) is: node.child_indices[0];
+                //	our leaf list length (vector len within leaf_list_children) is: node.child_indices[1];
+                //	our own index will be stored in leaf_lists at the matching index
+                if node.node_type != NODETYPE_LEAFLIST {
+                    //if not yet a Leaf-List, must have been a Leaf-Body -- its body starts the list
+                    node.node_type = NODETYPE_LEAFLIST;
+                    node.child_indices[0] = self.leaf_list_children.len() as u32;
+                    node.child_indices[1] = 1;
+                    let first = OctreeNode::new_leaf_body(node_a_position, node_a_mass);
+                    self.leaf_list_children.push(vec![first]);
+                    self.leaf_lists.push(index);
+                }
+                //then the new body gets a Leaf-Body node of its own
+                let leaf_list = node.child_indices[0] as usize;
+                node.child_indices[1] += 1;
+                let child = OctreeNode::new_leaf_body(position, mass);
+                self.leaf_list_children[leaf_list].push(child);
+                return;
+            }
+
+            //we are not at max_depth, and we aren't a Dummy or a leaf with room, so we should
+            //sift our body down
+            extents /= 2.0;
+            if held != 0 {
+                //if we are a full leaf, our current bodies move down into children of their own
+                //we aren't at max_depth, so we become an Interior
+                let bodies = if node.node_type == NODETYPE_LEAFLIST {
+                    let leaf_list = node.child_indices[0] as usize;
+                    std::mem::take(&mut self.leaf_list_children[leaf_list])
+                } else {
+                    vec![OctreeNode::new_leaf_body(node_a_position, node_a_mass)]
+                };
+                let node = &mut self.nodes[index];
+                node.node_type = NODETYPE_INTERIOR;
+                node.child_indices = [0; 8];
+                for body in bodies {
+                    let (position_a, mass_a) = (body.center_of_mass, body.total_mass);
+                    let ci_a = node_index_for_child(center, position_a);
+                    let child_a = self.ensure_has_child(index, ci_a);
+                    let center_a = center + extents * extent_weights(ci_a);
+                    self.insert(position_a, mass_a, child_a, depth + 1, center_a, extents);
+                }
+            }
+
+            //now, whether we were Interior or a leaf before, we are now Interior, and carry on
+            //into the new body's child, splitting it again if it's full
+            let ci_b = node_index_for_child(center, position);
+            index = self.ensure_has_child(index, ci_b);
+            center += extents * extent_weights(ci_b);
+            depth += 1;
+        }
+    }
+
+    /// Index of child `ci` of node `index`, adding it as a dummy if need be.
+    fn ensure_has_child(&mut self, index: usize, ci: usize) -> usize {
+        if self.nodes[index].child_indices[ci] == 0 {
+            let i = self.nodes.len();
+            self.nodes.push(OctreeNode::new_dummy());
+            self.nodes[index].child_indices[ci] = i as u32;
+        }
+        self.nodes[index].child_indices[ci] as usize
+    }
+
+    /// The flat tree, with every Leaf-List's Leaf-Body nodes appended as a contiguous block.
+    fn finish(mut self) -> Vec<OctreeNode> {
+        //each Leaf-List's child_indices[0] becomes the index of its block's first node
+        let leaf_lists = self.leaf_lists.into_iter().zip(self.leaf_list_children);
+        for (leaf_list, mut leaf_children) in leaf_lists {
+            //Leaf-Lists that filled up and split since hold nothing any more
+            if self.nodes[leaf_list].node_type != NODETYPE_LEAFLIST {
+                continue;
+            }
+            self.nodes[leaf_list].child_indices[0] = self.nodes.len() as u32;
+            self.nodes[leaf_list].child_indices[1] = leaf_children.len() as u32;
+            self.nodes.append(&mut leaf_children);
+        }
+        self.nodes
+    }
+}
+
 fn node_index_for_child(node_center: Vec3, child_position: Vec3) -> usize {
     let mut i = 0b000;
     if child_position.x >= node_center.x {
@@ -228,3 +386,293 @@ fn extent_weights(ci: usize) -> Vec3 {
         z: -1.0 + (2.0 * ((ci & 0b001) as f32)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::DVec3;
+    use proptest::prelude::*;
+
+    const WORLD_SIZE: f32 = 1000.0;
+
+    /// The Leaf-Body nodes reachable from the root, through Interiors and Leaf-Lists alike.
+    fn leaf_bodies(nodes: &[OctreeNode]) -> Vec<&OctreeNode> {
+        let mut leaves = vec![];
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &nodes[index as usize];
+            match node.node_type {
+                NODETYPE_LEAFBODY => leaves.push(node),
+                NODETYPE_LEAFLIST => {
+                    let [first, len, ..] = node.child_indices;
+                    stack.extend(first..first + len);
+                }
+                NODETYPE_INTERIOR => {
+                    stack.extend(node.child_indices.iter().filter(|child| **child != 0));
+                }
+                _ => {}
+            }
+        }
+        leaves
+    }
+
+    /// The Leaf-Body nodes met following the ropes, opening every node on the way.
+    fn roped_leaf_bodies(nodes: &[OctreeNode]) -> Vec<&OctreeNode> {
+        let mut leaves = vec![];
+        let mut index = 0;
+        loop {
+            let node = &nodes[index as usize];
+            if node.node_type == NODETYPE_LEAFBODY {
+                leaves.push(node);
+            }
+            index = node.next;
+            if index == 0 {
+                break;
+            }
+        }
+        leaves
+    }
+
+    fn sorted(bodies: impl Iterator<Item = (Vec3, f32)>) -> Vec<[f32; 4]> {
+        let mut bodies: Vec<[f32; 4]> = bodies.map(|(p, m)| [p.x, p.y, p.z, m]).collect();
+        bodies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        bodies
+    }
+
+    /// Depths of the Leaf-Lists reachable from the root, with the bodies each holds.
+    fn leaf_lists(nodes: &[OctreeNode]) -> Vec<(u32, u32)> {
+        let mut leaf_lists = vec![];
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &nodes[index as usize];
+            match node.node_type {
+                NODETYPE_LEAFLIST => leaf_lists.push((depth, node.child_indices[1])),
+                NODETYPE_INTERIOR => {
+                    let children = node.child_indices.iter().filter(|child| **child != 0);
+                    stack.extend(children.map(|child| (*child, depth + 1)));
+                }
+                _ => {}
+            }
+        }
+        leaf_lists
+    }
+
+    fn assert_preserved(positions: &[Vec3], masses: &[f32], shape: TreeShape) {
+        for builder in TreeBuilder::value_variants() {
+            assert_preserved_by(*builder, positions, masses, shape);
+        }
+    }
+
+    fn assert_preserved_by(
+        builder: TreeBuilder,
+        positions: &[Vec3],
+        masses: &[f32],
+        shape: TreeShape,
+    ) {
+        let nodes = builder.build(positions, masses, shape);
+
+        //every body is stored exactly once, unchanged
+        let leaves = leaf_bodies(&nodes);
+        assert_eq!(leaves.len(), positions.len());
+        let stored = leaves.iter().map(|leaf| (leaf.center_of_mass, leaf.total_mass));
+        let given = positions.iter().copied().zip(masses.iter().copied());
+        assert_eq!(sorted(stored), sorted(given));
+
+        //and met in the same order following the ropes
+        let roped = roped_leaf_bodies(&nodes);
+        assert_eq!(roped.len(), leaves.len());
+        assert!(roped.iter().rev().zip(&leaves).all(|(a, b)| std::ptr::eq(*a, *b)));
+
+        //leaves only hold more than leaf_size bodies at max_depth, and never go deeper
+        for (depth, len) in leaf_lists(&nodes) {
+            assert!(depth <= shape.max_depth);
+            assert!(len >= 2 && (len <= shape.leaf_size || depth == shape.max_depth));
+        }
+
+        if positions.is_empty() {
+            assert_eq!(nodes[0].node_type, NODETYPE_DUMMY);
+            return;
+        }
+        let total_mass: f64 = masses.iter().map(|m| *m as f64).sum();
+        let center_of_mass = positions
+            .iter()
+            .zip(masses)
+            .map(|(p, m)| p.as_dvec3() * *m as f64)
+            .sum::<DVec3>()
+            / total_mass;
+        let root = &nodes[0];
+        assert!((root.total_mass as f64 - total_mass).abs() <= 1e-5 * total_mass);
+        let error = (root.center_of_mass.as_dvec3() - center_of_mass).length();
+        assert!(error <= 1e-5 * WORLD_SIZE as f64, "centre of mass is {error} out");
+    }
+
+    #[test]
+    fn empty() {
+        assert_preserved(&[], &[], TreeShape::default());
+    }
+
+    #[test]
+    fn single_body() {
+        for builder in TreeBuilder::value_variants() {
+            let nodes = builder.build(&[Vec3::splat(10.0)], &[2.0], TreeShape::default());
+            assert_eq!(nodes.len(), 1);
+            assert_eq!(nodes[0].node_type, NODETYPE_LEAFBODY);
+            assert_eq!(nodes[0].total_mass, 2.0);
+        }
+    }
+
+    #[test]
+    fn coincident_bodies_share_a_leaf_list() {
+        let positions = [Vec3::splat(10.0); 3];
+        let masses = [1.0, 2.0, 3.0];
+        for builder in TreeBuilder::value_variants() {
+            let nodes = builder.build(&positions, &masses, TreeShape::default());
+            let leaf_list = nodes.iter().find(|node| node.node_type == NODETYPE_LEAFLIST).unwrap();
+            assert_eq!(leaf_list.child_indices[1], 3);
+            assert_eq!(leaf_list.total_mass, 6.0);
+        }
+        assert_preserved(&positions, &masses, TreeShape::default());
+    }
+
+    #[test]
+    fn full_leaves_split() {
+        let positions = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(1.0, 1.0, 1.0),
+        ];
+        let masses = [1.0; 5];
+        let shape = TreeShape {
+            leaf_size: 5,
+            max_depth: 16,
+        };
+        for builder in TreeBuilder::value_variants() {
+            let nodes = builder.build(&positions, &masses, shape);
+            assert_eq!(nodes[0].node_type, NODETYPE_LEAFLIST);
+            assert_eq!(nodes[0].child_indices[1], 5);
+            let shape = TreeShape {
+                leaf_size: 4,
+                ..shape
+            };
+            let nodes = builder.build(&positions, &masses, shape);
+            assert_eq!(nodes[0].node_type, NODETYPE_INTERIOR);
+        }
+    }
+
+    #[test]
+    fn distant_bodies_get_leaves_of_their_own() {
+        //far outside the world, where they'd once have been binned into the same edge cell
+        let positions = [
+            Vec3::new(-1e6, 0.0, 0.0),
+            Vec3::new(-1e6, 100.0, 0.0),
+            Vec3::new(1e6, 0.0, 0.0),
+        ];
+        let masses = [1.0; 3];
+        for builder in TreeBuilder::value_variants() {
+            let nodes = builder.build(&positions, &masses, TreeShape::default());
+            assert_eq!(leaf_bodies(&nodes).len(), 3);
+            assert!(nodes.iter().all(|node| node.node_type != NODETYPE_LEAFLIST));
+        }
+    }
+
+    #[test]
+    fn massless_octants_stay_finite() {
+        //the massless bodies get an octant to themselves, whose node has no mass to weigh by
+        let positions = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(10.0, 0.0, 0.0),
+            Vec3::new(900.0, 900.0, 900.0),
+            Vec3::new(910.0, 900.0, 900.0),
+        ];
+        let masses = [1.0, 3.0, 0.0, 0.0];
+        for builder in TreeBuilder::value_variants() {
+            let mut nodes = builder.build(&positions, &masses, TreeShape::default());
+            add_quadrupoles(&mut nodes);
+            for node in &nodes {
+                assert!(node.center_of_mass.is_finite());
+                assert!(node.quadrupole_diagonal.is_finite());
+                assert!(node.quadrupole_off_diagonal.is_finite());
+            }
+            assert_eq!(nodes[0].center_of_mass, Vec3::new(7.5, 0.0, 0.0));
+        }
+    }
+
+    fn position() -> impl Strategy<Value = Vec3> {
+        //reaching outside the world too, which the root grows to fit
+        let coordinate = -0.5 * WORLD_SIZE..1.5 * WORLD_SIZE;
+        let coordinates = (coordinate.clone(), coordinate.clone(), coordinate);
+        coordinates.prop_map(|(x, y, z)| Vec3::new(x, y, z))
+    }
+
+    fn tree_shape() -> impl Strategy<Value = TreeShape> {
+        (1u32..6, 0..=MAX_TREE_DEPTH).prop_map(|(leaf_size, max_depth)| TreeShape {
+            leaf_size,
+            max_depth,
+        })
+    }
+
+    fn bodies() -> impl Strategy<Value = (Vec<Vec3>, Vec<f32>)> {
+        let bodies = prop::collection::vec((position(), 0.01f32..100.0), 0..300);
+        bodies.prop_map(|bodies| bodies.into_iter().unzip())
+    }
+
+    proptest! {
+        #[test]
+        fn bodies_are_preserved((positions, masses) in bodies(), shape in tree_shape()) {
+            assert_preserved(&positions, &masses, shape);
+        }
+
+        #[test]
+        fn coincident_bodies_are_preserved(
+            sites in prop::collection::vec(position(), 1..4),
+            bodies in prop::collection::vec((any::<prop::sample::Index>(), 0.01f32..100.0), 1..300),
+            shape in tree_shape(),
+        ) {
+            let positions: Vec<Vec3> = bodies.iter().map(|(site, _)| *site.get(&sites)).collect();
+            let masses: Vec<f32> = bodies.iter().map(|(_, mass)| *mass).collect();
+            assert_preserved(&positions, &masses, shape);
+        }
+
+        #[test]
+        fn quadrupoles_match_direct_sum((positions, masses) in bodies(), shape in tree_shape()) {
+            prop_assume!(!positions.is_empty());
+            for builder in TreeBuilder::value_variants() {
+                let mut nodes = builder.build(&positions, &masses, shape);
+                add_quadrupoles(&mut nodes);
+                let root = &nodes[0];
+                let center = root.center_of_mass.as_dvec3();
+                let mut diagonal = DVec3::ZERO;
+                let mut off_diagonal = DVec3::ZERO;
+                let mut scale = 0.0;
+                for (position, mass) in positions.iter().zip(&masses) {
+                    let (d, m) = (position.as_dvec3() - center, *mass as f64);
+                    diagonal += m * (3.0 * d * d - DVec3::splat(d.dot(d)));
+                    off_diagonal += 3.0 * m * DVec3::new(d.x * d.y, d.x * d.z, d.y * d.z);
+                    scale += m * d.dot(d);
+                }
+                let error = (root.quadrupole_diagonal.as_dvec3() - diagonal).length()
+                    + (root.quadrupole_off_diagonal.as_dvec3() - off_diagonal).length();
+                prop_assert!(error <= 1e-4 * scale, "quadrupole is {} out of {}", error, scale);
+            }
+        }
+
+        #[test]
+        fn builders_agree((positions, masses) in bodies(), tree_shape in tree_shape()) {
+            let shape = |builder: TreeBuilder| {
+                let nodes = builder.build(&positions, &masses, tree_shape);
+                let mut shape: Vec<[f32; 7]> = nodes
+                    .iter()
+                    .map(|node| {
+                        let (min, max) = (node.pos_min, node.pos_max);
+                        [node.node_type as f32, min.x, min.y, min.z, max.x, max.y, max.z]
+                    })
+                    .collect();
+                shape.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                shape
+            };
+            prop_assert_eq!(shape(TreeBuilder::Insertion), shape(TreeBuilder::Morton));
+        }
+    }
+}
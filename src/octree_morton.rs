@@ -0,0 +1,160 @@
+use crate::octree_maxdepth::{
+    root_cell, OctreeNode, TreeShape, NODETYPE_INTERIOR, NODETYPE_LEAFBODY, NODETYPE_LEAFLIST,
+};
+use glam::Vec3;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// Bits of each coordinate in a Morton key; 3 * 21 = 63 bits, enough for cells finer than
+/// `MAX_TREE_DEPTH`.
+const KEY_BITS: u32 = 21;
+
+/// Build the same tree as `OctreeNode::new_tree`, in parallel.
+///
+/// Bodies are sorted by Morton (Z-order) key, so every cell's bodies are a contiguous run of the
+/// sorted order. The tree is then split top-down a level at a time, with each level's cells
+/// split in parallel, and its masses and centres of mass summed bottom-up a level at a time.
+/// Nodes come out breadth-first rather than in insertion order, with the Leaf-Lists' bodies
+/// appended after them as before.
+pub fn new_tree(positions: &[Vec3], masses: &[f32], shape: TreeShape) -> Vec<OctreeNode> {
+    let (center, extents) = root_cell(positions);
+    let (corner, size) = (center - extents, 2.0 * extents);
+    let mut bodies: Vec<(u64, u32)> = positions
+        .par_iter()
+        .enumerate()
+        .map(|(n, position)| (morton_key(*position, corner, size), n as u32))
+        .collect();
+    bodies.par_sort_unstable();
+    if bodies.is_empty() {
+        return vec![OctreeNode::new_dummy()];
+    }
+
+    //cells in the order their nodes will take, and the range of them at each depth
+    let mut cells = vec![Cell::new(0..bodies.len(), 0, shape)];
+    let mut levels = Vec::new();
+    levels.push(0..1);
+    while let Some(level) = levels.last().cloned() {
+        let depth = levels.len() as u32 - 1;
+        let splits: Vec<Vec<(usize, Range<usize>)>> = cells[level.clone()]
+            .par_iter()
+            .map(|cell| cell.split(&bodies, depth))
+            .collect();
+
+        let mut next = level.end;
+        for (index, children) in level.clone().zip(&splits) {
+            for (ci, _) in children {
+                cells[index].child_indices[*ci] = next as u32;
+                next += 1;
+            }
+        }
+        if next == level.end {
+            break;
+        }
+        let children = splits.into_iter().flatten();
+        cells.extend(children.map(|(_, bodies)| Cell::new(bodies, depth + 1, shape)));
+        levels.push(level.end..next);
+    }
+
+    let leaf_bodies = |run: &Range<usize>| -> Vec<OctreeNode> {
+        let bodies = bodies[run.clone()].iter().map(|(_, n)| *n as usize);
+        let leaves = bodies.map(|n| OctreeNode::new_leaf_body(positions[n], masses[n]));
+        leaves.collect()
+    };
+    let mut nodes: Vec<OctreeNode> = (0..cells.len()).map(|_| OctreeNode::new_dummy()).collect();
+    for level in levels.into_iter().rev() {
+        let (upper, lower) = nodes.split_at_mut(level.end);
+        let offset = level.end;
+        upper[level.clone()]
+            .par_iter_mut()
+            .zip(&cells[level])
+            .for_each(|(node, cell)| {
+                match cell.node_type {
+                    NODETYPE_LEAFBODY => {
+                        let n = bodies[cell.bodies.start].1 as usize;
+                        node.become_leaf_body(positions[n], masses[n]);
+                    }
+                    NODETYPE_LEAFLIST => node.sum_children(leaf_bodies(&cell.bodies).iter()),
+                    _ => {
+                        let children = cell.child_indices.iter().filter(|child| **child != 0);
+                        node.sum_children(children.map(|child| &lower[*child as usize - offset]));
+                    }
+                }
+                node.node_type = cell.node_type;
+                node.child_indices = cell.child_indices;
+            });
+    }
+
+    //each Leaf-List's bodies go after every other node, as a contiguous block
+    for (index, cell) in cells.iter().enumerate() {
+        if cell.node_type == NODETYPE_LEAFLIST {
+            nodes[index].child_indices[0] = nodes.len() as u32;
+            nodes[index].child_indices[1] = cell.bodies.len() as u32;
+            nodes.append(&mut leaf_bodies(&cell.bodies));
+        }
+    }
+    nodes
+}
+
+/// A node's run of the sorted bodies, and what it becomes.
+struct Cell {
+    bodies: Range<usize>,
+    node_type: u32,
+    child_indices: [u32; 8],
+}
+
+impl Cell {
+    fn new(bodies: Range<usize>, depth: u32, shape: TreeShape) -> Self {
+        let node_type = if bodies.len() == 1 {
+            NODETYPE_LEAFBODY
+        } else if shape.is_leaf(bodies.len(), depth) {
+            NODETYPE_LEAFLIST
+        } else {
+            NODETYPE_INTERIOR
+        };
+        Self {
+            bodies,
+            node_type,
+            child_indices: [0; 8],
+        }
+    }
+
+    /// The non-empty octants of an Interior at `depth`, with their runs of `bodies`.
+    fn split(&self, bodies: &[(u64, u32)], depth: u32) -> Vec<(usize, Range<usize>)> {
+        if self.node_type != NODETYPE_INTERIOR {
+            return vec![];
+        }
+        let shift = 3 * (KEY_BITS - 1 - depth);
+        let octant = |key: u64| (key >> shift) as usize & 0b111;
+        let run = &bodies[self.bodies.clone()];
+        let mut start = 0;
+        (0..8)
+            .filter_map(|ci| {
+                let end = run.partition_point(|(key, _)| octant(*key) <= ci);
+                let children = self.bodies.start + start..self.bodies.start + end;
+                start = end;
+                (!children.is_empty()).then_some((ci, children))
+            })
+            .collect()
+    }
+}
+
+/// Z-order key of the cell holding `position` in the root cube from `corner` spanning `size`,
+/// interleaving x, y, z from the most significant bit down, as `node_index_for_child` orders
+/// octants. Bodies on the cube's far faces are binned into the cells below them.
+fn morton_key(position: Vec3, corner: Vec3, size: f32) -> u64 {
+    let cells = (1u64 << KEY_BITS) as f32;
+    let cell = ((position - corner) / size * cells).floor();
+    let cell = cell.clamp(Vec3::ZERO, Vec3::splat(cells - 1.0));
+    (spread(cell.x as u64) << 2) | (spread(cell.y as u64) << 1) | spread(cell.z as u64)
+}
+
+/// Spread the low 21 bits of `x` out to every third bit.
+fn spread(x: u64) -> u64 {
+    let mut x = x & 0x1f_ffff;
+    x = (x | x << 32) & 0x1f_0000_0000_ffff;
+    x = (x | x << 16) & 0x1f_0000_ff00_00ff;
+    x = (x | x << 8) & 0x100f_00f0_0f00_f00f;
+    x = (x | x << 4) & 0x10c3_0c30_c30c_30c3;
+    x = (x | x << 2) & 0x1249_2492_4924_9249;
+    x
+}
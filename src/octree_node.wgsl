@@ -0,0 +1,20 @@
+// Octree nodes as laid out by `OctreeNode` in src/octree_maxdepth.rs; the start of the
+// Barnes-Hut force and tree building shaders.
+
+struct OctreeNode {
+	center_of_mass: vec3<f32>,
+    pos_min: vec3<f32>,
+    pos_max: vec3<f32>,
+    quadrupole_diagonal: vec3<f32>,
+    quadrupole_off_diagonal: vec3<f32>,
+    range: f32,
+	total_mass: f32,
+	child_indices: array<u32, 8>,
+	node_type: u32,
+	next: u32,
+	skip: u32,
+};
+let NODETYPE_DUMMY: u32 = 0u;
+let NODETYPE_LEAFBODY: u32 = 1u;
+let NODETYPE_LEAFLIST: u32 = 2u;
+let NODETYPE_INTERIOR: u32 = 3u;
@@ -0,0 +1,32 @@
+// The pull between a pair of bodies, shared by the direct and Barnes-Hut force kernels so they
+// can't drift apart; it comes after softening.wgsl, whose kernels it uses.
+
+// The original weakening of the pull on bodies heading towards each other, which made up for
+// fixed time steps overshooting close encounters; see `ForceModel::Stylised`
+fn stylised_bias(vel: vec3<f32>, dist_vec: vec3<f32>) -> f32 {
+		//acos(a dot b)/(magA * magB)
+		//mag(a) = 2-norm(a) = distance(0vec, a)
+	//start with the angle between the accelerator and the current velocity
+    let bias = acos(
+        dot(vel, dist_vec) / (distance(vec3(0.0), dist_vec) * distance(vec3(0.0), vel) + 1.0)
+    );
+	//pow to rein in the extremes
+    return pow(bias, .15);
+}
+
+// Acceleration of a body at `pos` moving at `vel` towards one at `other_pos`, softened by
+// `softening`
+fn pair_acceleration(
+    pos: vec3<f32>,
+    vel: vec3<f32>,
+    softening: f32,
+    other_pos: vec3<f32>,
+    other_mass: f32
+) -> vec3<f32> {
+    let dist_vec = other_pos - pos;
+    var g = params.g * other_mass * softened_force(dot(dist_vec, dist_vec), softening);
+    if params.force_model == FORCE_MODEL_STYLISED {
+        g *= stylised_bias(vel, dist_vec);
+    }
+    return g * dist_vec;
+}
@@ -0,0 +1,128 @@
+// Parallel reductions over the kinematics buffers, for diagnostics without reading every body
+// back to the CPU. `reduce_bodies` leaves one partial result per workgroup, which
+// `reduce_partials` (dispatched as a single workgroup) then folds into `partials[0]`.
+
+struct Moments {
+    mass: f32,
+    kinetic_energy: f32,
+    max_speed: f32,
+    max_acceleration: f32,
+    momentum: vec3<f32>,
+    angular_momentum: vec3<f32>,
+    // sum of mass * position, divided by the total mass on the CPU to give the centre of mass
+    mass_moment: vec3<f32>,
+    bounds_min: vec3<f32>,
+    bounds_max: vec3<f32>,
+};
+
+@group(0) @binding(0) var<storage, read> masses: array<f32>;
+@group(1) @binding(0) var<storage, read_write> positions: array<vec3<f32>>;
+@group(1) @binding(1) var<storage, read_write> velocities: array<vec3<f32>>;
+@group(1) @binding(2) var<storage, read_write> accelerations: array<vec3<f32>>;
+@group(2) @binding(0) var<storage, read_write> partials: array<Moments>;
+
+var<workgroup> scratch: array<Moments, 128>;
+
+fn empty() -> Moments {
+    let inf = 3.4e38;
+    return Moments(0.0, 0.0, 0.0, 0.0, vec3(0.0), vec3(0.0), vec3(0.0), vec3(inf), vec3(-inf));
+}
+
+fn is_nan(x: f32) -> bool {
+    return (bitcast<u32>(x) & 0x7fffffffu) > 0x7f800000u;
+}
+
+// `max` that keeps a NaN rather than leaving it to the implementation, so a blown-up run shows
+// in the diagnostics instead of looking still
+fn max_or_nan(a: f32, b: f32) -> f32 {
+    if is_nan(b) {
+        return b;
+    }
+    return select(max(a, b), a, is_nan(a));
+}
+
+fn combine(a: Moments, b: Moments) -> Moments {
+    return Moments(
+        a.mass + b.mass,
+        a.kinetic_energy + b.kinetic_energy,
+        max_or_nan(a.max_speed, b.max_speed),
+        max_or_nan(a.max_acceleration, b.max_acceleration),
+        a.momentum + b.momentum,
+        a.angular_momentum + b.angular_momentum,
+        a.mass_moment + b.mass_moment,
+        min(a.bounds_min, b.bounds_min),
+        max(a.bounds_max, b.bounds_max),
+    );
+}
+
+// Tree reduction of `scratch` within the workgroup; the result ends up in `scratch[0]`
+fn reduce_scratch(local_id: u32) {
+    var stride = 64u;
+    loop {
+        workgroupBarrier();
+        if local_id < stride {
+            scratch[local_id] = combine(scratch[local_id], scratch[local_id + stride]);
+        }
+        stride = stride / 2u;
+        if stride == 0u {
+            break;
+        }
+    }
+    workgroupBarrier();
+}
+
+@compute
+@workgroup_size(128)
+fn reduce_bodies(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(workgroup_id) workgroup_id: vec3<u32>,
+) {
+    let i = global_id.x;
+    var moments = empty();
+    if i < arrayLength(&masses) {
+        let mass = masses[i];
+        let pos = positions[i];
+        let vel = velocities[i];
+        let momentum = mass * vel;
+        moments = Moments(
+            mass,
+            0.5 * mass * dot(vel, vel),
+            length(vel),
+            length(accelerations[i]),
+            momentum,
+            cross(pos, momentum),
+            mass * pos,
+            pos,
+            pos,
+        );
+    }
+    scratch[local_id.x] = moments;
+
+    reduce_scratch(local_id.x);
+    if local_id.x == 0u {
+        partials[workgroup_id.x] = scratch[0];
+    }
+}
+
+@compute
+@workgroup_size(128)
+fn reduce_partials(@builtin(local_invocation_id) local_id: vec3<u32>) {
+    // Every partial is read into workgroup memory before the barriers in `reduce_scratch`, so
+    // writing the result back over `partials[0]` is safe
+    var moments = empty();
+    var i = local_id.x;
+    loop {
+        if i >= arrayLength(&partials) {
+            break;
+        }
+        moments = combine(moments, partials[i]);
+        i += 128u;
+    }
+    scratch[local_id.x] = moments;
+
+    reduce_scratch(local_id.x);
+    if local_id.x == 0u {
+        partials[0] = scratch[0];
+    }
+}
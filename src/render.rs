@@ -0,0 +1,323 @@
+use crate::gpu::{create_buffer_init, device_descriptor};
+use crate::Bodies;
+use encase::{ShaderType, UniformBuffer};
+use glam::{Vec2, Vec3};
+use std::borrow::Cow;
+use std::sync::Arc;
+use wgpu::util::{BufferInitDescriptor, DeviceExt};
+use wgpu::*;
+use winit::dpi::PhysicalSize;
+use winit::event::VirtualKeyCode;
+use winit::window::Window;
+
+const MASS_BINDING: u32 = 0;
+const DENSITIES_BINDING: u32 = 1;
+const EMITTERS_BINDING: u32 = 2;
+const POS_BINDING: u32 = 0;
+
+/// GPU device and a surface configured for presenting to a window.
+pub struct WindowContext {
+    pub surface: Surface,
+    pub config: SurfaceConfiguration,
+    pub device: Arc<Device>,
+    pub queue: Arc<Queue>,
+}
+
+impl WindowContext {
+    pub async fn new(window: &Window) -> Self {
+        // Setup GPU adapter/surface
+        let instance = Instance::new(Backends::all());
+        let surface = unsafe { instance.create_surface(window) };
+        let adapter = instance
+            .request_adapter(&RequestAdapterOptions {
+                compatible_surface: Some(&surface),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let (device, queue) = adapter
+            .request_device(&device_descriptor(&adapter), None)
+            .await
+            .unwrap();
+        let size = window.inner_size();
+        let config = SurfaceConfiguration {
+            usage: TextureUsages::RENDER_ATTACHMENT,
+            format: surface.get_supported_formats(&adapter)[0],
+            width: size.width,
+            height: size.height,
+            present_mode: PresentMode::Fifo,
+            alpha_mode: surface.get_supported_alpha_modes(&adapter)[0],
+        };
+        surface.configure(&device, &config);
+
+        Self {
+            surface,
+            config,
+            device: Arc::new(device),
+            queue: Arc::new(queue),
+        }
+    }
+
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        self.config.width = size.width;
+        self.config.height = size.height;
+        self.surface.configure(&self.device, &self.config);
+    }
+}
+
+/// Ray traces the bodies to the screen with `trace.wgsl`.
+pub struct Renderer {
+    static_bind_group: BindGroup,
+    positions_bind_group_layout: BindGroupLayout,
+    camera_bind_group_layout: BindGroupLayout,
+    trace_pipeline: RenderPipeline,
+    pub camera: Camera,
+}
+
+impl Renderer {
+    pub fn new(device: &Device, format: TextureFormat, bodies: &Bodies) -> Self {
+        // Setup GPU buffers
+        let mass_buffer =
+            create_buffer_init(device, "mass_buffer", &bodies.masses, BufferUsages::STORAGE);
+        let densities_buffer = create_buffer_init(
+            device,
+            "densities_buffer",
+            &bodies.densities,
+            BufferUsages::STORAGE,
+        );
+        let emitters_buffer = create_buffer_init(
+            device,
+            "emitters_buffer",
+            &bodies.emitters,
+            BufferUsages::STORAGE,
+        );
+
+        // Create bind group layouts
+        let static_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("static_bind_group_layout"),
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: MASS_BINDING,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: DENSITIES_BINDING,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: EMITTERS_BINDING,
+                        visibility: ShaderStages::FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Storage { read_only: true },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let positions_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("positions_bind_group_layout"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: POS_BINDING,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let camera_bind_group_layout =
+            device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                label: Some("camera_bind_group_layout"),
+                entries: &[BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        // Create bind groups
+        let static_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("static_bind_group"),
+            layout: &static_bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: MASS_BINDING,
+                    resource: mass_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: DENSITIES_BINDING,
+                    resource: densities_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: EMITTERS_BINDING,
+                    resource: emitters_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        // Compile render pipeline/shader
+        let trace_shader = device.create_shader_module(ShaderModuleDescriptor {
+            label: Some("trace_shader"),
+            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("trace.wgsl"))),
+        });
+        let trace_pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("trace_pipeline_layout"),
+            bind_group_layouts: &[
+                &static_bind_group_layout,
+                &positions_bind_group_layout,
+                &camera_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+        let trace_pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("trace_pipeline"),
+            layout: Some(&trace_pipeline_layout),
+            vertex: VertexState {
+                module: &trace_shader,
+                entry_point: "fullscreen_vertex_shader",
+                buffers: &[],
+            },
+            primitive: PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            fragment: Some(FragmentState {
+                module: &trace_shader,
+                entry_point: "trace",
+                targets: &[Some(format.into())],
+            }),
+            multiview: None,
+        });
+
+        let camera = Camera {
+            position: bodies.positions.first().copied().unwrap_or_default(),
+            ..Default::default()
+        };
+
+        Self {
+            static_bind_group,
+            positions_bind_group_layout,
+            camera_bind_group_layout,
+            trace_pipeline,
+            camera,
+        }
+    }
+
+    /// Render the bodies whose positions are stored in `pos_buffer` to `view`.
+    pub fn render(&self, device: &Device, queue: &Queue, view: &TextureView, pos_buffer: &Buffer) {
+        let mut trace_cmd_encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("trace_cmd_encoder"),
+        });
+
+        // Create camera uniform
+        let mut camera_buffer = UniformBuffer::new(Vec::new());
+        camera_buffer.write(&self.camera).unwrap();
+        let camera_buffer = camera_buffer.into_inner();
+        let camera_buffer = device.create_buffer_init(&BufferInitDescriptor {
+            label: Some("camera_buffer"),
+            contents: &camera_buffer,
+            usage: BufferUsages::UNIFORM,
+        });
+        let camera_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("camera_bind_group"),
+            layout: &self.camera_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: 0,
+                resource: camera_buffer.as_entire_binding(),
+            }],
+        });
+
+        let positions_bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("positions_bind_group"),
+            layout: &self.positions_bind_group_layout,
+            entries: &[BindGroupEntry {
+                binding: POS_BINDING,
+                resource: pos_buffer.as_entire_binding(),
+            }],
+        });
+
+        // Queue render job
+        {
+            let mut trace_pass = trace_cmd_encoder.begin_render_pass(&RenderPassDescriptor {
+                label: Some("trace_pass"),
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view,
+                    resolve_target: None,
+                    ops: Operations::default(),
+                })],
+                depth_stencil_attachment: None,
+            });
+            trace_pass.set_pipeline(&self.trace_pipeline);
+            trace_pass.set_bind_group(0, &self.static_bind_group, &[]);
+            trace_pass.set_bind_group(1, &positions_bind_group, &[]);
+            trace_pass.set_bind_group(2, &camera_bind_group, &[]);
+            trace_pass.draw(0..3, 0..1);
+        }
+
+        queue.submit(Some(trace_cmd_encoder.finish()));
+    }
+}
+
+#[derive(ShaderType, Default)]
+pub struct Camera {
+    pub position: Vec3,
+    pub angle_elevation: Vec2,
+}
+
+impl Camera {
+    /// Apply a camera control key press (arrows to look around, WASDQE to move).
+    pub fn handle_key(&mut self, vkc: VirtualKeyCode) {
+        let camera_direction: Vec3 = Vec3 {
+            x: f32::sin(self.angle_elevation.x) * f32::cos(self.angle_elevation.y),
+            y: f32::sin(self.angle_elevation.y),
+            z: f32::cos(self.angle_elevation.x) * f32::cos(self.angle_elevation.y),
+        };
+        let camera_plane_x: Vec3 = Vec3 {
+            x: -f32::cos(self.angle_elevation.x),
+            y: 0.0,
+            z: f32::sin(self.angle_elevation.x),
+        };
+        let camera_plane_y: Vec3 = Vec3 {
+            x: -f32::sin(self.angle_elevation.y) * f32::sin(self.angle_elevation.x),
+            y: f32::cos(self.angle_elevation.y),
+            z: -f32::sin(self.angle_elevation.y) * f32::cos(self.angle_elevation.x),
+        };
+        match vkc {
+            VirtualKeyCode::Left => self.angle_elevation.x += 0.1,
+            VirtualKeyCode::Right => self.angle_elevation.x -= 0.1,
+            VirtualKeyCode::Up => self.angle_elevation.y -= 0.1,
+            VirtualKeyCode::Down => self.angle_elevation.y += 0.1,
+            VirtualKeyCode::A => self.position -= camera_plane_x,
+            VirtualKeyCode::D => self.position += camera_plane_x,
+            VirtualKeyCode::Q => self.position -= camera_plane_y,
+            VirtualKeyCode::E => self.position += camera_plane_y,
+            VirtualKeyCode::S => self.position -= camera_direction,
+            VirtualKeyCode::W => self.position += camera_direction,
+            _ => {}
+        }
+    }
+}
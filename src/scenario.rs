@@ -0,0 +1,320 @@
+use crate::bodies::seeded_rng;
+use crate::force::ForceModel;
+use crate::integrator::Integrator;
+use crate::models::{self, DiskGalaxy};
+use crate::octree_maxdepth::TreeBuilder;
+use crate::softening::SofteningKernel;
+use crate::{solar_system, Bodies, Parameters};
+use glam::Vec3;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Initial conditions and parameters loaded from a TOML scenario file.
+///
+/// ```toml
+/// seed = 42
+///
+/// [parameters]
+/// time_step = 0.05
+///
+/// [[bodies]]
+/// mass = 500.0
+/// position = [125.0, 125.0, 125.0]
+/// emitter = true
+///
+/// [[generators]]
+/// distribution = "uniform_cube"
+/// n_bodies = 100
+/// velocity = [0.0, 0.5, 0.0]
+/// ```
+///
+/// Explicit `bodies` come first, followed by the output of each generator block in order.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub parameters: ParameterOverrides,
+    #[serde(default)]
+    pub bodies: Vec<BodySpec>,
+    #[serde(default)]
+    pub generators: Vec<GeneratorSpec>,
+}
+
+/// Parameters set by a scenario; anything left out keeps its command-line or default value.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ParameterOverrides {
+    pub world_size: Option<f32>,
+    pub time_step: Option<f32>,
+    pub adaptive: Option<bool>,
+    pub eta: Option<f32>,
+    pub min_time_step: Option<f32>,
+    pub max_time_step: Option<f32>,
+    pub max_rung: Option<u32>,
+    pub g: Option<f32>,
+    pub softening: Option<f32>,
+    pub softening_kernel: Option<SofteningKernel>,
+    pub theta: Option<f32>,
+    pub quadrupole: Option<bool>,
+    pub tree_builder: Option<TreeBuilder>,
+    pub leaf_size: Option<u32>,
+    pub max_depth: Option<u32>,
+    pub force_model: Option<ForceModel>,
+    pub integrator: Option<Integrator>,
+}
+
+/// A single body given explicitly in a scenario.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct BodySpec {
+    pub mass: f32,
+    #[serde(default = "default_density")]
+    pub density: f32,
+    pub position: Vec3,
+    #[serde(default)]
+    pub velocity: Vec3,
+    #[serde(default)]
+    pub emitter: bool,
+}
+
+/// A block of bodies produced by a generator, then shifted by `offset` and `velocity`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct GeneratorSpec {
+    #[serde(flatten)]
+    pub distribution: Distribution,
+    #[serde(default)]
+    pub offset: Vec3,
+    #[serde(default)]
+    pub velocity: Vec3,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "distribution", rename_all = "snake_case")]
+pub enum Distribution {
+    /// Bodies at rest, uniform in the middle of the world cube (the default without a scenario).
+    UniformCube { n_bodies: usize },
+    /// Plummer sphere in equilibrium, with `scale_radius` the Plummer radius.
+    Plummer {
+        n_bodies: usize,
+        total_mass: f32,
+        scale_radius: f32,
+    },
+    /// Hernquist profile in equilibrium, with `scale_radius` the break radius.
+    Hernquist {
+        n_bodies: usize,
+        total_mass: f32,
+        scale_radius: f32,
+    },
+    /// King model in equilibrium, with `scale_radius` the core radius and `w0` the dimensionless
+    /// central potential (typically 1 to 12; higher is more concentrated).
+    King {
+        n_bodies: usize,
+        total_mass: f32,
+        scale_radius: f32,
+        w0: f32,
+    },
+    /// Rotating exponential disk galaxy, optionally with a bulge and halo.
+    DiskGalaxy(DiskGalaxy),
+    /// Two disk galaxies on a parabolic encounter orbit; see [`models::parabolic_encounter`].
+    GalaxyCollision {
+        primary: DiskGalaxy,
+        secondary: DiskGalaxy,
+        pericenter: f32,
+        separation: f32,
+    },
+    /// The Sun and planets at J2000, in AU, solar masses and days; unless given, `g` is
+    /// [`solar_system::G`] to match.
+    SolarSystem,
+}
+
+#[derive(Debug)]
+pub enum ScenarioError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self, ScenarioError> {
+        let contents = fs::read_to_string(path).map_err(ScenarioError::Io)?;
+        toml::from_str(&contents).map_err(ScenarioError::Parse)
+    }
+
+    /// The parameters the scenario sets: those it gives, and `g` in the units of its
+    /// generators if it leaves that out.
+    pub fn parameter_overrides(&self) -> ParameterOverrides {
+        let units_g = self
+            .generators
+            .iter()
+            .find_map(|generator| match generator.distribution {
+                Distribution::SolarSystem => Some(solar_system::G),
+                _ => None,
+            });
+        ParameterOverrides {
+            g: self.parameters.g.or(units_g),
+            ..self.parameters
+        }
+    }
+
+    /// Build the scenario's bodies. Generator block `i` is seeded with `seed + i`.
+    pub fn bodies(&self, seed: u64, parameters: &Parameters) -> Bodies {
+        let mut bodies = Bodies::default();
+        for body in &self.bodies {
+            bodies.push(
+                body.mass,
+                body.density,
+                body.position,
+                body.velocity,
+                body.emitter,
+            );
+        }
+        for (i, generator) in self.generators.iter().enumerate() {
+            let seed = seed.wrapping_add(i as u64);
+            let mut generated = generator.distribution.generate(seed, parameters);
+            generated.translate(generator.offset, generator.velocity);
+            bodies.append(generated);
+        }
+
+        // The renderer needs at least one light source
+        if bodies.emitters.is_empty() && !bodies.is_empty() {
+            bodies.emitters.push(0);
+        }
+        bodies
+    }
+}
+
+impl ParameterOverrides {
+    /// Apply these overrides to `parameters`, except for those `keep` says to leave alone.
+    pub fn apply(&self, parameters: &mut Parameters, keep: impl Fn(&str) -> bool) {
+        fn set<T: Copy>(value: Option<T>, target: &mut T, keep: bool) {
+            if let Some(value) = value {
+                if !keep {
+                    *target = value;
+                }
+            }
+        }
+        let p = parameters;
+        set(self.world_size, &mut p.world_size, keep("world_size"));
+        set(self.time_step, &mut p.time_step, keep("time_step"));
+        set(self.adaptive, &mut p.adaptive, keep("adaptive"));
+        set(self.eta, &mut p.eta, keep("eta"));
+        set(
+            self.min_time_step,
+            &mut p.min_time_step,
+            keep("min_time_step"),
+        );
+        set(
+            self.max_time_step,
+            &mut p.max_time_step,
+            keep("max_time_step"),
+        );
+        set(self.max_rung, &mut p.max_rung, keep("max_rung"));
+        set(self.g, &mut p.g, keep("g"));
+        set(self.softening, &mut p.softening, keep("softening"));
+        set(
+            self.softening_kernel,
+            &mut p.softening_kernel,
+            keep("softening_kernel"),
+        );
+        set(self.theta, &mut p.theta, keep("theta"));
+        set(self.quadrupole, &mut p.quadrupole, keep("quadrupole"));
+        set(self.tree_builder, &mut p.tree_builder, keep("tree_builder"));
+        set(self.leaf_size, &mut p.leaf_size, keep("leaf_size"));
+        set(self.max_depth, &mut p.max_depth, keep("max_depth"));
+        set(self.force_model, &mut p.force_model, keep("force_model"));
+        set(self.integrator, &mut p.integrator, keep("integrator"));
+    }
+}
+
+impl From<&Parameters> for ParameterOverrides {
+    fn from(parameters: &Parameters) -> Self {
+        Self {
+            world_size: Some(parameters.world_size),
+            time_step: Some(parameters.time_step),
+            adaptive: Some(parameters.adaptive),
+            eta: Some(parameters.eta),
+            min_time_step: Some(parameters.min_time_step),
+            max_time_step: Some(parameters.max_time_step),
+            max_rung: Some(parameters.max_rung),
+            g: Some(parameters.g),
+            softening: Some(parameters.softening),
+            softening_kernel: Some(parameters.softening_kernel),
+            theta: Some(parameters.theta),
+            quadrupole: Some(parameters.quadrupole),
+            tree_builder: Some(parameters.tree_builder),
+            leaf_size: Some(parameters.leaf_size),
+            max_depth: Some(parameters.max_depth),
+            force_model: Some(parameters.force_model),
+            integrator: Some(parameters.integrator),
+        }
+    }
+}
+
+impl Distribution {
+    /// Generate bodies; equilibrium models are centred in the world cube, at rest, and the solar
+    /// system on the origin.
+    pub fn generate(&self, seed: u64, parameters: &Parameters) -> Bodies {
+        let rng = &mut seeded_rng(seed);
+        let g = parameters.g;
+        let mut bodies = match *self {
+            Distribution::UniformCube { n_bodies } => {
+                return Bodies::random_cube(n_bodies, parameters.world_size, seed);
+            }
+            Distribution::Plummer {
+                n_bodies,
+                total_mass,
+                scale_radius,
+            } => models::plummer(rng, n_bodies, total_mass, scale_radius, g),
+            Distribution::Hernquist {
+                n_bodies,
+                total_mass,
+                scale_radius,
+            } => models::hernquist(rng, n_bodies, total_mass, scale_radius, g),
+            Distribution::King {
+                n_bodies,
+                total_mass,
+                scale_radius,
+                w0,
+            } => models::king(rng, n_bodies, total_mass, scale_radius, w0, g),
+            //left heliocentric, about the origin, where f32 keeps the inner orbits most precise
+            Distribution::SolarSystem => return solar_system::bodies(),
+            Distribution::DiskGalaxy(ref galaxy) => {
+                models::disk_galaxy(rng, galaxy, g, parameters.softening)
+            }
+            Distribution::GalaxyCollision {
+                ref primary,
+                ref secondary,
+                pericenter,
+                separation,
+            } => models::parabolic_encounter(
+                rng,
+                primary,
+                secondary,
+                pericenter,
+                separation,
+                g,
+                parameters.softening,
+            ),
+        };
+        bodies.translate(Vec3::splat(parameters.world_size / 2.0), Vec3::ZERO);
+        bodies
+    }
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScenarioError::Io(e) => write!(f, "could not read scenario: {e}"),
+            ScenarioError::Parse(e) => write!(f, "invalid scenario: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ScenarioError {}
+
+fn default_density() -> f32 {
+    1.0
+}
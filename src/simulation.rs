@@ -0,0 +1,34 @@
+use crate::diagnostics::Moments;
+use glam::Vec3;
+
+/// Common interface over the CPU and GPU n-body backends.
+///
+/// Implementations are initialized from a [`crate::Bodies`] set, advanced with [`Simulation::step`],
+/// and can have their current state read back at any point (for GPU backends this blocks on a copy
+/// from device memory).
+pub trait Simulation {
+    /// Advance the simulation by one step of length `dt`.
+    fn step(&mut self, dt: f32);
+
+    /// Number of bodies being simulated.
+    fn n_bodies(&self) -> usize;
+
+    fn positions(&mut self) -> Vec<Vec3>;
+    fn velocities(&mut self) -> Vec<Vec3>;
+    fn accelerations(&mut self) -> Vec<Vec3>;
+    /// Time derivatives of the accelerations; only kept up to date by integrators that use them.
+    fn jerks(&mut self) -> Vec<Vec3>;
+    /// Block step rung of every body; all zero unless block steps are in use.
+    fn rungs(&mut self) -> Vec<u32>;
+
+    /// Kinetic energy, momenta, centre of mass and bounds of the current state. GPU backends
+    /// reduce these on the device rather than reading every body back.
+    fn moments(&mut self) -> Moments;
+
+    /// Replace the accelerations and jerks carried over from the previous step, e.g. when
+    /// restoring a snapshot.
+    fn set_accelerations(&mut self, accelerations: &[Vec3], jerks: &[Vec3]);
+
+    /// Put the bodies on the given block step rungs, e.g. when restoring a snapshot.
+    fn set_rungs(&mut self, rungs: &[u32]);
+}
@@ -0,0 +1,346 @@
+use crate::force::ForceModel;
+use crate::integrator::Integrator;
+use crate::octree_maxdepth::TreeBuilder;
+use crate::softening::SofteningKernel;
+use crate::{Bodies, Parameters, Simulation};
+use clap::ValueEnum;
+use glam::Vec3;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use winit::event::VirtualKeyCode;
+
+/// First bytes of every snapshot file.
+pub const MAGIC: [u8; 4] = *b"NBSN";
+/// Current version of the snapshot format, bumped whenever the layout changes.
+pub const VERSION: u32 = 9;
+
+/// The full state of a simulation at one step, as saved to and loaded from snapshot files.
+///
+/// The format is little-endian throughout: a header of `MAGIC`, `VERSION` (u32), the number of
+/// bodies and of emitters (u64 each), the step (u64), the time (f64) and the `Parameters`
+/// (the f32s in declaration order, then the integrator and the adaptive flag as u32s, the
+/// adaptive step settings as f32s, and the max rung, force model, softening kernel, tree builder,
+/// quadrupole flag, leaf size and max depth as u32s),
+/// followed by one array per field of `Bodies` and then the accelerations, jerks and rungs, with
+/// vectors stored as three f32s.
+///
+/// Older versions can still be read: version 1 lacks the integrator and jerks, versions 1 and 2
+/// lack the adaptive step settings, versions 1 to 3 lack the rungs, versions 1 to 4 the force
+/// model, versions 1 to 5 the softening kernel, versions 1 to 6 the tree builder, versions 1 to
+/// 7 the quadrupole flag and versions 1 to 8 the leaf size and max depth. Anything missing keeps
+/// its default.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub step: u64,
+    pub time: f64,
+    pub parameters: Parameters,
+    pub bodies: Bodies,
+    /// Accelerations and jerks from the most recent step, which the integrators carry over to
+    /// the next; empty before the first step
+    pub accelerations: Vec<Vec3>,
+    pub jerks: Vec<Vec3>,
+    /// Block step rung of every body, in use if `parameters.max_rung` is non-zero
+    pub rungs: Vec<u32>,
+}
+
+impl Snapshot {
+    /// Snapshot of `bodies` before the first step.
+    pub fn initial(bodies: Bodies, parameters: &Parameters) -> Self {
+        Self {
+            step: 0,
+            time: 0.0,
+            parameters: *parameters,
+            accelerations: Vec::new(),
+            jerks: Vec::new(),
+            rungs: Vec::new(),
+            bodies,
+        }
+    }
+
+    /// Read back the current state of `simulation`, taking masses, densities and the like from
+    /// `bodies`.
+    pub fn capture<S: Simulation>(
+        simulation: &mut S,
+        bodies: &Bodies,
+        step: u64,
+        time: f64,
+        parameters: &Parameters,
+    ) -> Self {
+        let mut bodies = bodies.clone();
+        bodies.positions = simulation.positions();
+        bodies.velocities = simulation.velocities();
+        Self {
+            step,
+            time,
+            parameters: *parameters,
+            bodies,
+            accelerations: simulation.accelerations(),
+            jerks: simulation.jerks(),
+            rungs: simulation.rungs(),
+        }
+    }
+
+    /// Hand the accelerations, jerks and rungs over to `simulation`, so its next step continues
+    /// exactly where the captured one left off. Initial snapshots have none, so this does nothing.
+    pub fn restore_history<S: Simulation>(&self, simulation: &mut S) {
+        if !self.accelerations.is_empty() {
+            simulation.set_accelerations(&self.accelerations, &self.jerks);
+        }
+        if !self.rungs.is_empty() {
+            simulation.set_rungs(&self.rungs);
+        }
+    }
+
+    /// Handle the windowed front-ends' snapshot keys: F5 saves the current state of `simulation`
+    /// to `path`, and F9 loads it back, returning it for the caller to resume from. The outcome
+    /// is reported rather than returned, so a missing or bad file never ends the run.
+    pub fn handle_key<S: Simulation>(
+        key: VirtualKeyCode,
+        path: &Path,
+        simulation: &mut S,
+        bodies: &Bodies,
+        step: u64,
+        time: f64,
+        parameters: &Parameters,
+    ) -> Option<Self> {
+        let result = match key {
+            VirtualKeyCode::F5 => {
+                let snapshot = Self::capture(simulation, bodies, step, time, parameters);
+                snapshot.save(path).map(|()| {
+                    eprintln!("saved step {step} to {}", path.display());
+                    None
+                })
+            }
+            VirtualKeyCode::F9 => Self::load(path).map(|snapshot| {
+                eprintln!("restored step {} from {}", snapshot.step, path.display());
+                Some(snapshot)
+            }),
+            _ => Ok(None),
+        };
+        result.unwrap_or_else(|e| {
+            eprintln!("{}: {e}", path.display());
+            None
+        })
+    }
+
+    /// Write this snapshot to `path`, replacing any existing file.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let bodies = &self.bodies;
+        let parameters = &self.parameters;
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&(bodies.len() as u64).to_le_bytes())?;
+        writer.write_all(&(bodies.emitters.len() as u64).to_le_bytes())?;
+        writer.write_all(&self.step.to_le_bytes())?;
+        writer.write_all(&self.time.to_le_bytes())?;
+        write_f32s(
+            writer,
+            &[
+                parameters.world_size,
+                parameters.time_step,
+                parameters.g,
+                parameters.softening,
+                parameters.theta,
+            ],
+        )?;
+        writer.write_all(&(parameters.integrator as u32).to_le_bytes())?;
+        writer.write_all(&(parameters.adaptive as u32).to_le_bytes())?;
+        write_f32s(
+            writer,
+            &[
+                parameters.eta,
+                parameters.min_time_step,
+                parameters.max_time_step,
+            ],
+        )?;
+        writer.write_all(&parameters.max_rung.to_le_bytes())?;
+        writer.write_all(&(parameters.force_model as u32).to_le_bytes())?;
+        writer.write_all(&(parameters.softening_kernel as u32).to_le_bytes())?;
+        writer.write_all(&(parameters.tree_builder as u32).to_le_bytes())?;
+        writer.write_all(&(parameters.quadrupole as u32).to_le_bytes())?;
+        writer.write_all(&parameters.leaf_size.to_le_bytes())?;
+        writer.write_all(&parameters.max_depth.to_le_bytes())?;
+
+        write_f32s(writer, &bodies.masses)?;
+        write_f32s(writer, &bodies.densities)?;
+        write_u32s(writer, &bodies.galaxies)?;
+        write_vec3s(writer, &bodies.positions)?;
+        write_vec3s(writer, &bodies.velocities)?;
+        write_vec3s(writer, &self.accelerations)?;
+        write_vec3s(writer, &self.jerks)?;
+        write_u32s(writer, &self.rungs)?;
+        write_u32s(writer, &bodies.emitters)
+    }
+
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not a snapshot file".to_string()));
+        }
+        let version = read_u32(reader)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid_data(format!(
+                "unsupported snapshot version {version}, expected {VERSION}"
+            )));
+        }
+
+        let n_bodies = read_len(reader)?;
+        let n_emitters = read_len(reader)?;
+        let step = u64::from_le_bytes(read_array(reader)?);
+        let time = f64::from_le_bytes(read_array(reader)?);
+        let mut parameters = Parameters {
+            world_size: read_f32(reader)?,
+            time_step: read_f32(reader)?,
+            g: read_f32(reader)?,
+            softening: read_f32(reader)?,
+            theta: read_f32(reader)?,
+            ..Default::default()
+        };
+        if version >= 2 {
+            let integrator = read_u32(reader)?;
+            parameters.integrator = *Integrator::value_variants()
+                .get(integrator as usize)
+                .ok_or_else(|| invalid_data(format!("unknown integrator {integrator}")))?;
+        }
+        if version >= 3 {
+            parameters.adaptive = read_u32(reader)? != 0;
+            parameters.eta = read_f32(reader)?;
+            parameters.min_time_step = read_f32(reader)?;
+            parameters.max_time_step = read_f32(reader)?;
+        }
+        if version >= 4 {
+            parameters.max_rung = read_u32(reader)?;
+        }
+        if version >= 5 {
+            let force_model = read_u32(reader)?;
+            parameters.force_model = *ForceModel::value_variants()
+                .get(force_model as usize)
+                .ok_or_else(|| invalid_data(format!("unknown force model {force_model}")))?;
+        }
+        if version >= 6 {
+            let kernel = read_u32(reader)?;
+            parameters.softening_kernel =
+                *SofteningKernel::value_variants()
+                    .get(kernel as usize)
+                    .ok_or_else(|| invalid_data(format!("unknown softening kernel {kernel}")))?;
+        }
+        if version >= 7 {
+            let builder = read_u32(reader)?;
+            parameters.tree_builder = *TreeBuilder::value_variants()
+                .get(builder as usize)
+                .ok_or_else(|| invalid_data(format!("unknown tree builder {builder}")))?;
+        }
+        if version >= 8 {
+            parameters.quadrupole = read_u32(reader)? != 0;
+        }
+        if version >= 9 {
+            parameters.leaf_size = read_u32(reader)?;
+            parameters.max_depth = read_u32(reader)?;
+        }
+
+        let masses = read_f32s(reader, n_bodies)?;
+        let densities = read_f32s(reader, n_bodies)?;
+        let galaxies = read_u32s(reader, n_bodies)?;
+        let positions = read_vec3s(reader, n_bodies)?;
+        let velocities = read_vec3s(reader, n_bodies)?;
+        let accelerations = read_vec3s(reader, n_bodies)?;
+        let jerks = if version >= 2 {
+            read_vec3s(reader, n_bodies)?
+        } else {
+            vec![Vec3::ZERO; n_bodies]
+        };
+        let rungs = if version >= 4 {
+            read_u32s(reader, n_bodies)?
+        } else {
+            Vec::new()
+        };
+        let emitters = read_u32s(reader, n_emitters)?;
+        if emitters.iter().any(|e| *e as usize >= n_bodies) {
+            return Err(invalid_data("emitter index out of range".to_string()));
+        }
+
+        Ok(Self {
+            step,
+            time,
+            parameters,
+            bodies: Bodies {
+                masses,
+                densities,
+                emitters,
+                galaxies,
+                positions,
+                velocities,
+            },
+            accelerations,
+            jerks,
+            rungs,
+        })
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_f32s(writer: &mut impl Write, values: &[f32]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|value| writer.write_all(&value.to_le_bytes()))
+}
+
+fn write_u32s(writer: &mut impl Write, values: &[u32]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|value| writer.write_all(&value.to_le_bytes()))
+}
+
+fn write_vec3s(writer: &mut impl Write, values: &[Vec3]) -> io::Result<()> {
+    values
+        .iter()
+        .try_for_each(|value| write_f32s(writer, &value.to_array()))
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    Ok(f32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_len(reader: &mut impl Read) -> io::Result<usize> {
+    let len = u64::from_le_bytes(read_array(reader)?);
+    usize::try_from(len).map_err(|_| invalid_data(format!("length {len} too large")))
+}
+
+fn read_f32s(reader: &mut impl Read, n: usize) -> io::Result<Vec<f32>> {
+    (0..n).map(|_| read_f32(reader)).collect()
+}
+
+fn read_u32s(reader: &mut impl Read, n: usize) -> io::Result<Vec<u32>> {
+    (0..n).map(|_| read_u32(reader)).collect()
+}
+
+fn read_vec3s(reader: &mut impl Read, n: usize) -> io::Result<Vec<Vec3>> {
+    (0..n)
+        .map(|_| Ok(Vec3::from_slice(&read_f32s(reader, 3)?)))
+        .collect()
+}
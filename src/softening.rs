@@ -0,0 +1,137 @@
+use clap::ValueEnum;
+use glam::Vec3;
+use rayon::prelude::*;
+use serde::Deserialize;
+
+/// Ratio of the spline kernel's support to the Plummer softening length with the same central
+/// potential, as in Gadget.
+pub const SPLINE_SUPPORT: f32 = 2.8;
+/// Neighbours whose distance sets the local density for adaptive softening.
+pub const ADAPTIVE_NEIGHBOURS: usize = 32;
+/// Adaptive softening length in units of the local mean interparticle spacing.
+pub const ADAPTIVE_ETA: f32 = 0.5;
+
+/// How gravity is softened at small separations, shared by every backend and the potential
+/// energy diagnostics. Each body has a softening length `ε`, and a pair uses the mean of theirs.
+///
+/// For a unit mass and `G`, `force` and `potential` stand in for `1 / r^3` and `-1 / r`.
+/// `softening.wgsl` has the same kernels for the GPU.
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SofteningKernel {
+    /// `1 / (r^2 + ε^2)^(3/2)`: never Newtonian, however far apart
+    #[default]
+    Plummer,
+    /// Monaghan's cubic spline, exactly Newtonian beyond `SPLINE_SUPPORT ε`, as in Gadget
+    Spline,
+    /// The spline with each body's `ε` following its local density, never below `softening`
+    Adaptive,
+}
+
+impl SofteningKernel {
+    /// Softening length of every body at `positions`, given the `softening` parameter.
+    ///
+    /// Adaptive lengths are `ADAPTIVE_ETA n^(-1/3)`, with `n` the number density within the
+    /// `ADAPTIVE_NEIGHBOURS`th nearest neighbour; finding it is a direct O(N^2) search.
+    pub fn lengths(self, positions: &[Vec3], softening: f32) -> Vec<f32> {
+        let k = ADAPTIVE_NEIGHBOURS.min(positions.len().saturating_sub(1));
+        if self != SofteningKernel::Adaptive || k == 0 {
+            return vec![softening; positions.len()];
+        }
+        positions
+            .par_iter()
+            .enumerate()
+            .map(|(n, position)| {
+                let mut distances_sqrd: Vec<f32> = (0..positions.len())
+                    .filter(|n2| *n2 != n)
+                    .map(|n2| positions[n2].distance_squared(*position))
+                    .collect();
+                let (_, kth, _) = distances_sqrd.select_nth_unstable_by(k - 1, f32::total_cmp);
+                adaptive_length(kth.sqrt(), k).max(softening)
+            })
+            .collect()
+    }
+
+    /// Acceleration towards a unit mass at squared distance `r_sqrd`, over its separation.
+    pub fn force(self, r_sqrd: f32, softening: f32) -> f32 {
+        match self {
+            SofteningKernel::Plummer => {
+                let s = r_sqrd + softening * softening;
+                1.0 / (s * s.sqrt())
+            }
+            SofteningKernel::Spline | SofteningKernel::Adaptive => {
+                let h = SPLINE_SUPPORT * softening;
+                let r = r_sqrd.sqrt();
+                if r >= h {
+                    return 1.0 / (r_sqrd * r);
+                }
+                let u = r / h;
+                let h3_inv = 1.0 / (h * h * h);
+                if u < 0.5 {
+                    h3_inv * (32.0 / 3.0 + u * u * (32.0 * u - 38.4))
+                } else {
+                    h3_inv
+                        * (64.0 / 3.0 - 48.0 * u + 38.4 * u * u
+                            - 32.0 / 3.0 * u * u * u
+                            - 1.0 / (15.0 * u * u * u))
+                }
+            }
+        }
+    }
+
+    /// Derivative of `force` with respect to `r`, over `r`; what the jerk needs.
+    pub fn force_slope(self, r_sqrd: f32, softening: f32) -> f32 {
+        match self {
+            SofteningKernel::Plummer => {
+                let s = r_sqrd + softening * softening;
+                -3.0 / (s * s * s.sqrt())
+            }
+            SofteningKernel::Spline | SofteningKernel::Adaptive => {
+                let h = SPLINE_SUPPORT * softening;
+                let r = r_sqrd.sqrt();
+                if r >= h {
+                    return -3.0 / (r_sqrd * r_sqrd * r);
+                }
+                let u = r / h;
+                let h5_inv = 1.0 / (h * h * h * h * h);
+                if u < 0.5 {
+                    h5_inv * (96.0 * u - 76.8)
+                } else {
+                    h5_inv * (76.8 - 48.0 / u - 32.0 * u + 1.0 / (5.0 * u * u * u * u * u))
+                }
+            }
+        }
+    }
+
+    /// Potential of a unit mass at distance `r`.
+    pub fn potential(self, r: f64, softening: f64) -> f64 {
+        match self {
+            SofteningKernel::Plummer => -1.0 / (r * r + softening * softening).sqrt(),
+            SofteningKernel::Spline | SofteningKernel::Adaptive => {
+                let h = SPLINE_SUPPORT as f64 * softening;
+                if r >= h {
+                    return -1.0 / r;
+                }
+                let u = r / h;
+                let w = if u < 0.5 {
+                    -2.8 + u * u * (16.0 / 3.0 + u * u * (6.4 * u - 9.6))
+                } else {
+                    -3.2 + 1.0 / (15.0 * u)
+                        + u * u * (32.0 / 3.0 + u * (-16.0 + u * (9.6 - 32.0 / 15.0 * u)))
+                };
+                w / h
+            }
+        }
+    }
+}
+
+/// Softening length shared by bodies with lengths `a` and `b`.
+pub fn pair_length(a: f32, b: f32) -> f32 {
+    0.5 * (a + b)
+}
+
+/// Adaptive softening length of a body whose `k`th nearest neighbour is at `distance`.
+fn adaptive_length(distance: f32, k: usize) -> f32 {
+    let number_density = k as f32 / (4.0 / 3.0 * std::f32::consts::PI * distance.powi(3));
+    ADAPTIVE_ETA * number_density.powf(-1.0 / 3.0)
+}
@@ -0,0 +1,101 @@
+// Softening kernels of src/softening.rs, for a unit mass and G, and the kernel that keeps each
+// body's softening length up to date when it's adaptive.
+
+let SOFTENING_PLUMMER: u32 = 0u;
+let SOFTENING_ADAPTIVE: u32 = 2u;
+let SPLINE_SUPPORT: f32 = 2.8;
+let ADAPTIVE_NEIGHBOURS: u32 = 32u;
+let ADAPTIVE_ETA: f32 = 0.5;
+let PI: f32 = 3.14159265;
+
+// Stands in for 1 / r^3
+fn softened_force(r_sqrd: f32, softening: f32) -> f32 {
+    if params.softening_kernel == SOFTENING_PLUMMER {
+        let s = r_sqrd + softening * softening;
+        return 1.0 / (s * sqrt(s));
+    }
+    let h = SPLINE_SUPPORT * softening;
+    let r = sqrt(r_sqrd);
+    if r >= h {
+        return 1.0 / (r_sqrd * r);
+    }
+    let u = r / h;
+    let h3_inv = 1.0 / (h * h * h);
+    if u < 0.5 {
+        return h3_inv * (32.0 / 3.0 + u * u * (32.0 * u - 38.4));
+    }
+    let u3 = u * u * u;
+    return h3_inv * (64.0 / 3.0 - 48.0 * u + 38.4 * u * u - 32.0 / 3.0 * u3 - 1.0 / (15.0 * u3));
+}
+
+// Derivative of `softened_force` with respect to r, over r
+fn softened_force_slope(r_sqrd: f32, softening: f32) -> f32 {
+    if params.softening_kernel == SOFTENING_PLUMMER {
+        let s = r_sqrd + softening * softening;
+        return -3.0 / (s * s * sqrt(s));
+    }
+    let h = SPLINE_SUPPORT * softening;
+    let r = sqrt(r_sqrd);
+    if r >= h {
+        return -3.0 / (r_sqrd * r_sqrd * r);
+    }
+    let u = r / h;
+    let h5_inv = 1.0 / (h * h * h * h * h);
+    if u < 0.5 {
+        return h5_inv * (96.0 * u - 76.8);
+    }
+    return h5_inv * (76.8 - 48.0 / u - 32.0 * u + 1.0 / (5.0 * u * u * u * u * u));
+}
+
+// Softening length shared by bodies with lengths `a` and `b`
+fn pair_length(a: f32, b: f32) -> f32 {
+    return 0.5 * (a + b);
+}
+
+// Adaptive softening length of body `i_id`, from the distance to its ADAPTIVE_NEIGHBOURSth nearest
+// neighbour, found by a direct search
+fn adaptive_length(i_id: u32) -> f32 {
+    let n_bodies = arrayLength(&masses);
+    let k = min(ADAPTIVE_NEIGHBOURS, n_bodies - 1u);
+    if k == 0u {
+        return params.softening;
+    }
+
+    // Squared distances of the k nearest so far, in ascending order
+    var nearest: array<f32, 32>; //ADAPTIVE_NEIGHBOURS
+    for (var m = 0u; m < k; m += 1u) {
+        nearest[m] = 3.4e38;
+    }
+    let pos = positions[i_id];
+    for (var i = 0u; i < n_bodies; i += 1u) {
+        let dist_vec = positions[i] - pos;
+        let r_sqrd = dot(dist_vec, dist_vec);
+        if i == i_id || r_sqrd >= nearest[k - 1u] {
+            continue;
+        }
+        var m = k - 1u;
+        loop {
+            if m == 0u || nearest[m - 1u] <= r_sqrd {
+                break;
+            }
+            nearest[m] = nearest[m - 1u];
+            m -= 1u;
+        }
+        nearest[m] = r_sqrd;
+    }
+
+    let distance = sqrt(nearest[k - 1u]);
+    let number_density = f32(k) / (4.0 / 3.0 * PI * distance * distance * distance);
+    return max(ADAPTIVE_ETA * pow(number_density, -1.0 / 3.0), params.softening);
+}
+
+// Set body i's softening length; only dispatched for adaptive softening
+@compute
+@workgroup_size(64)
+fn compute_softening_lengths(@builtin(global_invocation_id) global_invocation_id: vec3<u32>) {
+    let i_id = global_invocation_id.x;
+    if i_id >= arrayLength(&masses) {
+        return;
+    }
+    softening_lengths[i_id] = adaptive_length(i_id);
+}
@@ -0,0 +1,122 @@
+// The tables keep the precision of their sources, beyond what f32 can hold
+#![allow(clippy::excessive_precision)]
+
+use crate::Bodies;
+use glam::Vec3;
+
+/// Gravitational constant in AU³ / (M☉ day²), the Gaussian gravitational constant squared.
+pub const G: f32 = 0.000295912208;
+
+/// A body of the built-in solar system.
+pub struct Planet {
+    pub name: &'static str,
+    /// Mass in solar masses
+    pub mass: f32,
+    /// Heliocentric ecliptic position at J2000, in AU
+    pub position: Vec3,
+    /// Heliocentric ecliptic velocity at J2000, in AU/day
+    pub velocity: Vec3,
+    /// Sidereal orbital period in days
+    pub period: f32,
+}
+
+/// The planets at epoch J2000, from the mean orbital elements of Standish's "Keplerian
+/// Elements for Approximate Positions of the Major Planets" (JPL). Earth is the Earth-Moon
+/// barycentre.
+#[rustfmt::skip]
+pub const PLANETS: [Planet; 8] = [
+    Planet { name: "Mercury", mass: 1.6601e-7, period: 87.969,
+        position: Vec3::new(-0.130088620, -0.447292337, -0.024598820),
+        velocity: Vec3::new(2.136627519e-2, -6.447894582e-3, -2.487836504e-3) },
+    Planet { name: "Venus", mass: 2.4478383e-6, period: 224.701,
+        position: Vec3::new(-0.718316356, -0.032706662, 0.041015624),
+        velocity: Vec3::new(7.988295200e-4, -2.029484890e-2, -3.234660679e-4) },
+    Planet { name: "Earth", mass: 3.0404326e-6, period: 365.256,
+        position: Vec3::new(-0.177171249, 0.967214485, -0.000000258),
+        velocity: Vec3::new(-1.720314353e-2, -3.164259351e-3, 8.455214516e-10) },
+    Planet { name: "Mars", mass: 3.2271514e-7, period: 686.980,
+        position: Vec3::new(1.390667748, -0.013391064, -0.034461259),
+        velocity: Vec3::new(6.725918024e-4, 1.518782070e-2, 3.016233991e-4) },
+    Planet { name: "Jupiter", mass: 9.5479194e-4, period: 4332.59,
+        position: Vec3::new(3.998320940, 2.945710911, -0.101717815),
+        velocity: Vec3::new(-4.572054768e-3, 6.435787177e-3, 7.573120753e-5) },
+    Planet { name: "Saturn", mass: 2.8588598e-4, period: 10759.22,
+        position: Vec3::new(6.414784487, 6.545667465, -0.369146773),
+        velocity: Vec3::new(-4.281654188e-3, 3.893650861e-3, 1.024171338e-4) },
+    Planet { name: "Uranus", mass: 4.3662440e-5, period: 30688.5,
+        position: Vec3::new(14.425465883, -13.737645726, -0.238033120),
+        velocity: Vec3::new(2.681747333e-3, 2.663641064e-3, -2.487656959e-5) },
+    Planet { name: "Neptune", mass: 5.1513890e-5, period: 60182.0,
+        position: Vec3::new(16.804762812, -24.992709860, 0.127403210),
+        velocity: Vec3::new(2.583379951e-3, 1.768414622e-3, -9.594319342e-5) },
+];
+
+/// The Sun (body 0, the only light source) and the eight planets in order, in the barycentric
+/// frame. Units are AU, solar masses and days, so simulate with `G`.
+pub fn bodies() -> Bodies {
+    let mut bodies = Bodies::default();
+    bodies.push(1.0, 1.0, Vec3::ZERO, Vec3::ZERO, true);
+    for planet in &PLANETS {
+        bodies.push(planet.mass, 1.0, planet.position, planet.velocity, false);
+    }
+    bodies.to_center_of_mass_frame();
+    bodies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scenario::Scenario;
+    use crate::{CpuSimulation, Parameters, Simulation};
+    use std::f32::consts::TAU;
+    use std::path::Path;
+
+    #[test]
+    fn inner_planets_keep_their_periods() {
+        //built as the scenario file is run, so its units and placement are checked too
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios/solar_system.toml");
+        let scenario = Scenario::load(&path).unwrap();
+        let mut parameters = Parameters::default();
+        scenario.parameter_overrides().apply(&mut parameters, |_| false);
+        let mut simulation = CpuSimulation::new(&scenario.bodies(0, &parameters), &parameters);
+        let inner = &PLANETS[..4];
+        let heliocentric = |positions: &[Vec3], n: usize| positions[n + 1] - positions[0];
+        let angle = |offset: Vec3| offset.y.atan2(offset.x);
+
+        //unwrap each planet's heliocentric longitude, until it's gone all the way round once
+        let positions = simulation.positions();
+        let mut longitudes: Vec<f32> = (0..inner.len())
+            .map(|n| angle(heliocentric(&positions, n)))
+            .collect();
+        let mut travelled = vec![0.0; inner.len()];
+        let mut periods = vec![None; inner.len()];
+        let mut time = 0.0;
+        while periods.contains(&None) && time < 1000.0 {
+            simulation.step(parameters.time_step);
+            time += parameters.time_step;
+            let positions = simulation.positions();
+            for n in 0..inner.len() {
+                let longitude = angle(heliocentric(&positions, n));
+                let change = (longitude - longitudes[n] + TAU / 2.0).rem_euclid(TAU) - TAU / 2.0;
+                longitudes[n] = longitude;
+                if periods[n].is_none() && travelled[n] + change >= TAU {
+                    let overshoot = (travelled[n] + change - TAU) / change;
+                    periods[n] = Some(time - overshoot * parameters.time_step);
+                }
+                travelled[n] += change;
+            }
+        }
+
+        for (planet, period) in inner.iter().zip(periods) {
+            let period =
+                period.unwrap_or_else(|| panic!("{} didn't complete an orbit", planet.name));
+            let error = (period - planet.period).abs() / planet.period;
+            assert!(
+                error < 0.001,
+                "{} took {period} days rather than {}",
+                planet.name,
+                planet.period
+            );
+        }
+    }
+}